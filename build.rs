@@ -1,4 +1,5 @@
 pub fn main() {
     println!("cargo:rerun-if-changed=ext/layout.ld");
     println!("cargo:rerun-if-changed=ext/crt0.S");
+    println!("cargo:rerun-if-changed=ext/vectors.S");
 }
@@ -0,0 +1,106 @@
+/// Helpers for reading and changing the state of the ARMv8 core itself rather than a peripheral
+///
+/// Documentation for the system registers can be found in chapter D7 of the ARMv8 Architecture
+/// Reference Manual
+use core::fmt;
+
+///The exception levels of ARMv8, higher levels are more privileged.
+///
+///The boot code in ext/crt0.S drops from EL3 or EL2 down to EL1 before calling kmain, so the
+///kernel should always see EL1 here. EL0 is where user programs would run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ExceptionLevel {
+    EL0,
+    EL1,
+    EL2,
+    EL3,
+}
+
+impl fmt::Display for ExceptionLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ExceptionLevel::EL0 => "EL0",
+            ExceptionLevel::EL1 => "EL1",
+            ExceptionLevel::EL2 => "EL2",
+            ExceptionLevel::EL3 => "EL3",
+        };
+        f.write_str(name)
+    }
+}
+
+///Reads the exception level the core is currently running in from bits [3:2] of CurrentEL
+pub fn current_el() -> ExceptionLevel {
+    let el: u64;
+    unsafe {
+        asm!("mrs $0, CurrentEL" : "=r"(el) ::: "volatile");
+    }
+    match (el >> 2) & 0b11 {
+        0 => ExceptionLevel::EL0,
+        1 => ExceptionLevel::EL1,
+        2 => ExceptionLevel::EL2,
+        _ => ExceptionLevel::EL3,
+    }
+}
+
+///Unmasks IRQs on this core by clearing the I bit in DAIF
+pub fn enable_irqs() {
+    unsafe {
+        asm!("msr daifclr, #2" ::: "memory" : "volatile");
+    }
+}
+
+///Masks IRQs on this core by setting the I bit in DAIF
+pub fn disable_irqs() {
+    unsafe {
+        asm!("msr daifset, #2" ::: "memory" : "volatile");
+    }
+}
+
+///True if IRQs are currently masked on this core
+pub fn irqs_disabled() -> bool {
+    let daif: u64;
+    unsafe {
+        asm!("mrs $0, DAIF" : "=r"(daif) ::: "volatile");
+    }
+    //The I bit is bit 7
+    daif & (1 << 7) != 0
+}
+
+///Runs a closure with IRQs masked, restoring the previous mask afterwards.
+///This is the only locking needed around data shared with interrupt handlers on a single core
+pub fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+    let was_disabled = irqs_disabled();
+    disable_irqs();
+    let out = f();
+    if !was_disabled {
+        enable_irqs();
+    }
+    out
+}
+
+///Puts the core to sleep until an interrupt arrives. A pending interrupt wakes the core even if
+///it is masked, so checking for work with IRQs disabled and then calling this can't miss one
+pub fn wfi() {
+    unsafe {
+        asm!("wfi" :::: "volatile");
+    }
+}
+
+///Reads which core this is running on from the affinity level 0 field of MPIDR_EL1
+pub fn core_id() -> usize {
+    let mpidr: u64;
+    unsafe {
+        asm!("mrs $0, mpidr_el1" : "=r"(mpidr) ::: "volatile");
+    }
+    (mpidr & 0b11) as usize
+}
+
+///Busy waits for roughly this many cycles. Used where the hardware asks for a delay measured in
+///clock cycles rather than time, like the GPIO pull-up/down sequence
+pub fn delay_cycles(cycles: u32) {
+    for _ in 0..cycles {
+        unsafe {
+            asm!("nop" :::: "volatile");
+        }
+    }
+}
@@ -0,0 +1,286 @@
+/// Debounced push buttons on GPIO inputs
+///
+/// Mechanical switches bounce, making and breaking contact many times over a few milliseconds
+/// when pressed or released. A change of level only counts once the pin has held it for the
+/// debounce time.
+
+use arch::without_interrupts;
+use gpio::{Event, Gpio, Input, Pull};
+use timer::current_time;
+use timer_wheel::{self, TimerHandle};
+
+///Things a button can report
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ButtonEvent {
+    Press,
+    Release,
+    ///Reported once while the button is still held, after the long press time
+    LongPress,
+    ///Reported straight after the Press that completes a double click
+    DoubleClick,
+}
+
+///How many events can be waiting to be collected by poll
+const QUEUE: usize = 4;
+
+///Debounce time for each pin in microseconds, for the edge interrupt handler to know when to wake
+///the core up again to look at the settled level
+static mut SETTLE_MICROS: [u64; 54] = [0; 54];
+
+///The wake up waiting on the timer wheel for each pin. There is only ever one per pin, so a
+///bouncing switch can't fill the wheel up with them
+static mut WAKES: [Option<TimerHandle>; 54] = [None; 54];
+
+///Does nothing, the timer interrupt that runs it is enough to wake the core from wfi
+fn wake() {}
+
+///Wakes the core in so many microseconds to look at the pin again, replacing any wake up already
+///waiting for it. Returns false if the wheel is full
+fn schedule_wake(pin: u8, micros: u64) -> bool {
+    without_interrupts(|| unsafe {
+        cancel_wake(pin);
+        WAKES[pin as usize] = timer_wheel::schedule_after(micros, wake).ok();
+        WAKES[pin as usize].is_some()
+    })
+}
+
+fn cancel_wake(pin: u8) {
+    without_interrupts(|| unsafe {
+        if let Some(handle) = WAKES[pin as usize].take() {
+            timer_wheel::cancel(handle);
+        }
+    })
+}
+
+///Edge interrupt handler for buttons driven by interrupts. Every edge pushes the wake up back to
+///a debounce time after it. If the wheel is full there's nothing more to do here, the edge
+///interrupt has woken the core anyway and poll tries again
+fn on_edge(pin: u8) {
+    let settle = unsafe { SETTLE_MICROS[pin as usize] };
+    schedule_wake(pin, settle);
+}
+
+///A debounced button on an input pin.
+///
+///Call poll regularly to collect events. With with_interrupts the button wakes the core up when
+///there is something to look at, so the caller can sleep with wfi between polls instead
+pub struct Button {
+    gpio: Gpio<Input>,
+    ///If the pin reads low when the button is pressed (a switch to ground with a pull-up)
+    active_low: bool,
+    debounce: u64,
+    long_press: u64,
+    double_click: u64,
+    interrupt_driven: bool,
+    ///Set when poll couldn't get a wake up onto the timer wheel, see needs_polling
+    wake_failed: bool,
+
+    ///Debounced state
+    pressed: bool,
+    ///Level read on the last poll, and when it last changed
+    last_raw: bool,
+    last_change: u64,
+    ///When the current press started and if it has been reported as long yet
+    pressed_at: u64,
+    long_reported: bool,
+    ///If the current press was the second click of a double click
+    double_clicked: bool,
+    ///When the last short press was released, for spotting a double click
+    released_at: Option<u64>,
+
+    events: [Option<ButtonEvent>; QUEUE],
+}
+
+impl Button {
+    ///Constructor for a button wired between the pin and ground, the most common way. The
+    ///internal pull-up is turned on so the pin reads high when the button is up.
+    ///Times default to 20ms debounce, 1s long press and 400ms between clicks of a double click
+    pub fn new(gpio: Gpio<Input>) -> Button {
+        let gpio = gpio.with_pull(Pull::Up);
+        let mut button = Button {
+            gpio,
+            active_low: true,
+            debounce: 20_000,
+            long_press: 1_000_000,
+            double_click: 400_000,
+            interrupt_driven: false,
+            wake_failed: false,
+            pressed: false,
+            last_raw: false,
+            last_change: 0,
+            pressed_at: 0,
+            long_reported: false,
+            double_clicked: false,
+            released_at: None,
+            events: [None; QUEUE],
+        };
+        //Start from whatever the button is doing now rather than reporting it as a press
+        button.pressed = button.read_raw();
+        button.last_raw = button.pressed;
+        button.last_change = current_time();
+        button
+    }
+
+    ///For a button wired between the pin and 3.3V instead, using the internal pull-down
+    pub fn active_high(mut self) -> Self {
+        self.gpio.set_pull(Pull::Down);
+        self.active_low = false;
+        self.pressed = self.read_raw();
+        self.last_raw = self.pressed;
+        self
+    }
+
+    ///Set the debounce time in milliseconds via builder pattern
+    pub fn with_debounce(mut self, millis: u64) -> Self {
+        self.debounce = millis * 1000;
+        self.update_settle_time();
+        self
+    }
+
+    ///Set how long in milliseconds the button has to be held for a LongPress
+    pub fn with_long_press(mut self, millis: u64) -> Self {
+        self.long_press = millis * 1000;
+        self
+    }
+
+    ///Set the longest gap in milliseconds between clicks that still counts as a DoubleClick
+    pub fn with_double_click(mut self, millis: u64) -> Self {
+        self.double_click = millis * 1000;
+        self
+    }
+
+    ///Uses the GPIO edge interrupts to wake the core when the button changes, plus software
+    ///timers for when the level has settled and when a long press is due
+    pub fn with_interrupts(mut self) -> Self {
+        self.interrupt_driven = true;
+        self.update_settle_time();
+        self.gpio.enable_event(Event::RisingEdge);
+        self.gpio.enable_event(Event::FallingEdge);
+        self.gpio.on_event(on_edge);
+        self
+    }
+
+    fn update_settle_time(&self) {
+        unsafe {
+            SETTLE_MICROS[self.gpio.pin() as usize] = self.debounce;
+        }
+    }
+
+    ///The debounced state of the button
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    ///With interrupts, true when the timer wheel was too full to wake the core up for the next
+    ///thing the button is waiting for. The caller should keep polling instead of sleeping until
+    ///this goes back to false
+    pub fn needs_polling(&self) -> bool {
+        self.wake_failed
+    }
+
+    ///Reads the pin, true if it says the button is down
+    fn read_raw(&mut self) -> bool {
+        self.gpio.read_level() != self.active_low
+    }
+
+    ///Samples the button and returns the next event, if there is one. More than one event can
+    ///come out of a single change so keep calling until this returns None
+    pub fn poll(&mut self) -> Option<ButtonEvent> {
+        let now = current_time();
+        let raw = self.read_raw();
+        if raw != self.last_raw {
+            self.last_raw = raw;
+            self.last_change = now;
+        }
+
+        if raw != self.pressed && now - self.last_change >= self.debounce {
+            self.pressed = raw;
+            match raw {
+                true => self.on_press(now),
+                false => self.on_release(now),
+            }
+        }
+
+        if self.pressed && !self.long_reported && now - self.pressed_at >= self.long_press {
+            self.long_reported = true;
+            self.push(ButtonEvent::LongPress);
+        }
+
+        if self.interrupt_driven {
+            self.schedule_next_wake(now);
+        }
+        self.pop()
+    }
+
+    ///Keeps one wake up on the timer wheel for whichever comes first of the level settling and a
+    ///long press being due, or none if the button isn't waiting for either
+    fn schedule_next_wake(&mut self, now: u64) {
+        let settle = match self.last_raw != self.pressed {
+            true => Some(self.last_change + self.debounce),
+            false => None,
+        };
+        let long_press = match self.pressed && !self.long_reported {
+            true => Some(self.pressed_at + self.long_press),
+            false => None,
+        };
+        let next = match (settle, long_press) {
+            (Some(settle), Some(long_press)) => Some(settle.min(long_press)),
+            (settle, long_press) => settle.or(long_press),
+        };
+        let pin = self.gpio.pin();
+        self.wake_failed = match next {
+            Some(at) => !schedule_wake(pin, at.saturating_sub(now)),
+            None => {
+                cancel_wake(pin);
+                false
+            }
+        };
+    }
+
+    fn on_press(&mut self, now: u64) {
+        self.pressed_at = now;
+        self.long_reported = false;
+        self.double_clicked = false;
+        self.push(ButtonEvent::Press);
+        if let Some(released_at) = self.released_at.take() {
+            if now - released_at <= self.double_click {
+                self.double_clicked = true;
+                self.push(ButtonEvent::DoubleClick);
+            }
+        }
+    }
+
+    fn on_release(&mut self, now: u64) {
+        self.push(ButtonEvent::Release);
+        //A long press or the second click of a double click can't start another double click
+        self.released_at = match self.long_reported || self.double_clicked {
+            true => None,
+            false => Some(now),
+        };
+    }
+
+    ///Adds an event to the back of the queue, dropping it if nobody has been polling
+    fn push(&mut self, event: ButtonEvent) {
+        if let Some(slot) = self.events.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(event);
+        }
+    }
+
+    ///Takes the event off the front of the queue and shuffles the rest up
+    fn pop(&mut self) -> Option<ButtonEvent> {
+        let out = self.events[0];
+        for i in 1..QUEUE {
+            self.events[i - 1] = self.events[i];
+        }
+        self.events[QUEUE - 1] = None;
+        out
+    }
+}
+
+///Cancels the button's wake up. Dropping the Gpio after this switches its edge detection off and
+///removes on_edge, so the next owner of the pin doesn't get them
+impl Drop for Button {
+    fn drop(&mut self) {
+        cancel_wake(self.gpio.pin());
+    }
+}
@@ -0,0 +1,818 @@
+/// 2D drawing on a block of memory laid out as rows of pixels.
+///
+/// A Canvas doesn't care where its memory is, it can be a page of the framebuffer (see
+/// Framebuffer::canvas and Framebuffer::back_buffer) or any buffer in RAM, so pictures can be
+/// drawn off screen and checked byte by byte. Coordinates are signed so shapes can hang off the
+/// edges, everything is clipped to the clip rectangle which starts as the whole canvas.
+///
+/// Drawing a frame without flicker looks like:
+///
+/// let mut canvas = framebuffer.back_buffer();
+/// canvas.clear(BLACK);
+/// canvas.fill_circle(100, 100, 20, RED);
+/// framebuffer.flip()?;
+
+use core::ptr;
+use font;
+use mailbox::ColourOrder;
+
+///A colour with alpha, 255 alpha is solid and 0 is invisible
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Colour {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Colour {
+    ///A solid colour
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Colour {
+        Colour { r, g, b, a: 255 }
+    }
+
+    pub const fn rgba(r: u8, g: u8, b: u8, a: u8) -> Colour {
+        Colour { r, g, b, a }
+    }
+
+    ///Mixes this colour over another one by its alpha
+    pub fn blend_over(&self, under: Colour) -> Colour {
+        let a = self.a as u32;
+        let mix = |over: u8, under: u8| ((over as u32 * a + under as u32 * (255 - a)) / 255) as u8;
+        Colour {
+            r: mix(self.r, under.r),
+            g: mix(self.g, under.g),
+            b: mix(self.b, under.b),
+            a: 255,
+        }
+    }
+}
+
+pub const BLACK: Colour = Colour::rgb(0, 0, 0);
+pub const WHITE: Colour = Colour::rgb(255, 255, 255);
+pub const RED: Colour = Colour::rgb(255, 0, 0);
+pub const GREEN: Colour = Colour::rgb(0, 255, 0);
+pub const BLUE: Colour = Colour::rgb(0, 0, 255);
+pub const YELLOW: Colour = Colour::rgb(255, 255, 0);
+
+///How a pixel is stored, named from the most significant bit down. Pixels are little endian
+///in memory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    ///16 bits, 5 red, 6 green and 5 blue
+    Rgb565,
+    ///24 bits, 8 each
+    Rgb888,
+    ///32 bits, 8 each with alpha at the top
+    Argb8888,
+}
+
+impl PixelFormat {
+    ///The format for a framebuffer depth, if there is one
+    pub fn from_depth(depth: u32) -> Option<PixelFormat> {
+        match depth {
+            16 => Some(PixelFormat::Rgb565),
+            24 => Some(PixelFormat::Rgb888),
+            32 => Some(PixelFormat::Argb8888),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Rgb888 => 3,
+            PixelFormat::Argb8888 => 4,
+        }
+    }
+
+    ///Turns a colour into the value stored for a pixel
+    pub fn pack(&self, colour: Colour) -> u32 {
+        let (r, g, b, a) = (colour.r as u32, colour.g as u32, colour.b as u32, colour.a as u32);
+        match *self {
+            PixelFormat::Rgb565 => (r >> 3) << 11 | (g >> 2) << 5 | b >> 3,
+            PixelFormat::Rgb888 => r << 16 | g << 8 | b,
+            PixelFormat::Argb8888 => a << 24 | r << 16 | g << 8 | b,
+        }
+    }
+
+    ///Turns a stored pixel back into a colour. The low bits lost packing into 565 are filled
+    ///from the high bits so white stays white
+    pub fn unpack(&self, value: u32) -> Colour {
+        match *self {
+            PixelFormat::Rgb565 => {
+                let r = (value >> 11 & 0x1F) as u8;
+                let g = (value >> 5 & 0x3F) as u8;
+                let b = (value & 0x1F) as u8;
+                Colour::rgb(r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2)
+            }
+            PixelFormat::Rgb888 => Colour::rgb((value >> 16) as u8, (value >> 8) as u8, value as u8),
+            PixelFormat::Argb8888 => Colour::rgba(
+                (value >> 16) as u8,
+                (value >> 8) as u8,
+                value as u8,
+                (value >> 24) as u8,
+            ),
+        }
+    }
+}
+
+///An image to blit, RGBA with one byte each in that order, row after row with no gaps
+#[derive(Debug, Clone, Copy)]
+pub struct Sprite<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: &'a [u8],
+}
+
+impl<'a> Sprite<'a> {
+    ///Panics if there aren't enough pixels for the size
+    pub fn new(width: u32, height: u32, pixels: &'a [u8]) -> Sprite<'a> {
+        assert!(
+            pixels.len() >= width as usize * height as usize * 4,
+            "sprite data too short for {}x{}",
+            width,
+            height
+        );
+        Sprite {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> Colour {
+        let i = (y as usize * self.width as usize + x as usize) * 4;
+        Colour::rgba(
+            self.pixels[i],
+            self.pixels[i + 1],
+            self.pixels[i + 2],
+            self.pixels[i + 3],
+        )
+    }
+}
+
+///A rectangle of pixels that drawing is kept inside, right and bottom are one past the edge
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Clip {
+    left: i32,
+    top: i32,
+    right: i32,
+    bottom: i32,
+}
+
+///Something to draw on
+pub struct Canvas<'a> {
+    buffer: &'a mut [u8],
+    width: u32,
+    height: u32,
+    ///Bytes from one row to the next
+    pitch: usize,
+    format: PixelFormat,
+    order: ColourOrder,
+    clip: Clip,
+}
+
+impl<'a> Canvas<'a> {
+    ///Constructor, panics if the buffer is too small for the size
+    pub fn new(
+        buffer: &'a mut [u8],
+        width: u32,
+        height: u32,
+        pitch: usize,
+        format: PixelFormat,
+    ) -> Canvas<'a> {
+        assert!(
+            pitch >= width as usize * format.bytes_per_pixel()
+                && buffer.len() >= pitch * height as usize,
+            "buffer too small for a {}x{} canvas",
+            width,
+            height
+        );
+        Canvas {
+            buffer,
+            width,
+            height,
+            pitch,
+            format,
+            order: ColourOrder::Rgb,
+            clip: Clip {
+                left: 0,
+                top: 0,
+                right: width as i32,
+                bottom: height as i32,
+            },
+        }
+    }
+
+    ///Set the colour order, the framebuffer on some firmware stores blue in the high bits
+    pub fn with_order(mut self, order: ColourOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    ///Keeps drawing inside a rectangle, which is itself kept inside the canvas
+    pub fn set_clip(&mut self, x: i32, y: i32, width: u32, height: u32) {
+        self.clip = Clip {
+            left: x.max(0),
+            top: y.max(0),
+            right: x.saturating_add(width as i32).min(self.width as i32),
+            bottom: y.saturating_add(height as i32).min(self.height as i32),
+        };
+    }
+
+    ///Lets drawing go anywhere on the canvas again
+    pub fn reset_clip(&mut self) {
+        let (width, height) = (self.width, self.height);
+        self.set_clip(0, 0, width, height);
+    }
+
+    fn in_clip(&self, x: i32, y: i32) -> bool {
+        x >= self.clip.left && x < self.clip.right && y >= self.clip.top && y < self.clip.bottom
+    }
+
+    fn pack(&self, colour: Colour) -> u32 {
+        self.format.pack(self.swap(colour))
+    }
+
+    ///Swaps red and blue for BGR, which works both ways
+    fn swap(&self, colour: Colour) -> Colour {
+        match self.order {
+            ColourOrder::Rgb => colour,
+            ColourOrder::Bgr => Colour {
+                r: colour.b,
+                b: colour.r,
+                ..colour
+            },
+        }
+    }
+
+    ///Writes a packed pixel, the caller has checked it is inside the canvas
+    fn write(&mut self, x: i32, y: i32, value: u32) {
+        let bytes = self.format.bytes_per_pixel();
+        let start = y as usize * self.pitch + x as usize * bytes;
+        for (i, byte) in self.buffer[start..start + bytes].iter_mut().enumerate() {
+            *byte = (value >> (i * 8)) as u8;
+        }
+    }
+
+    fn read(&self, x: i32, y: i32) -> u32 {
+        let bytes = self.format.bytes_per_pixel();
+        let start = y as usize * self.pitch + x as usize * bytes;
+        self.buffer[start..start + bytes]
+            .iter()
+            .enumerate()
+            .fold(0, |value, (i, byte)| value | (*byte as u32) << (i * 8))
+    }
+
+    ///The colour of a pixel, None outside the canvas
+    pub fn pixel(&self, x: i32, y: i32) -> Option<Colour> {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return None;
+        }
+        Some(self.swap(self.format.unpack(self.read(x, y))))
+    }
+
+    pub fn set_pixel(&mut self, x: i32, y: i32, colour: Colour) {
+        if self.in_clip(x, y) {
+            let value = self.pack(colour);
+            self.write(x, y, value);
+        }
+    }
+
+    ///Fills everything inside the clip rectangle
+    pub fn clear(&mut self, colour: Colour) {
+        let clip = self.clip;
+        self.fill_rect(
+            clip.left,
+            clip.top,
+            (clip.right - clip.left).max(0) as u32,
+            (clip.bottom - clip.top).max(0) as u32,
+            colour,
+        );
+    }
+
+    pub fn fill_rect(&mut self, x: i32, y: i32, width: u32, height: u32, colour: Colour) {
+        let left = x.max(self.clip.left);
+        let top = y.max(self.clip.top);
+        let right = x.saturating_add(width as i32).min(self.clip.right);
+        let bottom = y.saturating_add(height as i32).min(self.clip.bottom);
+        let value = self.pack(colour);
+        for row in top..bottom {
+            for column in left..right {
+                self.write(column, row, value);
+            }
+        }
+    }
+
+    ///Outline of a rectangle, one pixel wide and inside the size given
+    pub fn rect(&mut self, x: i32, y: i32, width: u32, height: u32, colour: Colour) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        let right = x + width as i32 - 1;
+        let bottom = y + height as i32 - 1;
+        self.fill_rect(x, y, width, 1, colour);
+        self.fill_rect(x, bottom, width, 1, colour);
+        self.fill_rect(x, y, 1, height, colour);
+        self.fill_rect(right, y, 1, height, colour);
+    }
+
+    ///Straight line including both ends, using Bresenham's algorithm so it only needs integers.
+    ///The error term tracks how far the line is from the pixel centres and picks whether the
+    ///next pixel steps in x, y or both. The sums are done in i64 so ends far off the canvas
+    ///can't overflow
+    pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, colour: Colour) {
+        let (x0, y0, x1, y1) = (x0 as i64, y0 as i64, x1 as i64, y1 as i64);
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let step_x = if x0 < x1 { 1 } else { -1 };
+        let step_y = if y0 < y1 { 1 } else { -1 };
+        //The major axis moves one pixel every step, so only the steps that put it inside the
+        //clip rectangle can draw anything
+        let clip = self.clip;
+        let x_major = dx >= -dy;
+        let (major, minor, start, step, low, high) = if x_major {
+            (dx, -dy, x0, step_x, clip.left as i64, clip.right as i64 - 1)
+        } else {
+            (-dy, dx, y0, step_y, clip.top as i64, clip.bottom as i64 - 1)
+        };
+        let (first, last) = if step > 0 {
+            (low - start, high - start)
+        } else {
+            (start - high, start - low)
+        };
+        let (first, last) = (first.max(0), last.min(major));
+        if first > last {
+            return;
+        }
+        //Jump straight to the first of those steps. After k steps the minor axis has moved
+        //k * minor / major rounded to nearest with halves rounded down, and the error term is
+        //however far that rounding left it behind. The product fits in a u64
+        let half = major / 2;
+        let divisor = major.max(1) as u64;
+        let along = first as u64 * minor as u64 + half as u64;
+        let across = (along / divisor) as i64;
+        let behind = half - (along % divisor) as i64;
+        let (mut x, mut y, mut error) = if x_major {
+            (x0 + step_x * first, y0 + step_y * across, dx + dy + behind)
+        } else {
+            (x0 + step_x * across, y0 + step_y * first, dx + dy - behind)
+        };
+        for _ in first..last + 1 {
+            self.set_pixel(x as i32, y as i32, colour);
+            let double = 2 * error;
+            if double >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if double <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    ///True if a square reaching radius from a centre overlaps the clip rectangle
+    fn near_clip(&self, cx: i64, cy: i64, radius: i64) -> bool {
+        cx + radius >= self.clip.left as i64
+            && cx - radius < self.clip.right as i64
+            && cy + radius >= self.clip.top as i64
+            && cy - radius < self.clip.bottom as i64
+    }
+
+    ///set_pixel for points that might not fit in an i32
+    fn set_wide_pixel(&mut self, x: i64, y: i64, colour: Colour) {
+        if x >= self.clip.left as i64
+            && x < self.clip.right as i64
+            && y >= self.clip.top as i64
+            && y < self.clip.bottom as i64
+        {
+            self.set_pixel(x as i32, y as i32, colour);
+        }
+    }
+
+    ///A one pixel high fill_rect from left to right inclusive, for ends that might not fit in
+    ///an i32
+    fn wide_span(&mut self, left: i64, right: i64, y: i64, colour: Colour) {
+        if y < self.clip.top as i64 || y >= self.clip.bottom as i64 {
+            return;
+        }
+        let left = left.max(self.clip.left as i64);
+        let right = right.min(self.clip.right as i64 - 1);
+        if left <= right {
+            self.fill_rect(left as i32, y as i32, (right - left + 1) as u32, 1, colour);
+        }
+    }
+
+    ///Outline of a circle with the midpoint algorithm, working round one eighth of it and
+    ///mirroring each point into the other seven
+    pub fn circle(&mut self, cx: i32, cy: i32, radius: u32, colour: Colour) {
+        let (cx, cy) = (cx as i64, cy as i64);
+        let mut x = radius as i64;
+        if !self.near_clip(cx, cy, x) {
+            return;
+        }
+        let mut y = 0;
+        let mut error = 1 - x;
+        while x >= y {
+            let points = [(x, y), (y, x), (-y, x), (-x, y), (-x, -y), (-y, -x), (y, -x), (x, -y)];
+            for &(px, py) in points.iter() {
+                self.set_wide_pixel(cx + px, cy + py, colour);
+            }
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    ///Solid circle, the same points as circle joined up with horizontal lines
+    pub fn fill_circle(&mut self, cx: i32, cy: i32, radius: u32, colour: Colour) {
+        let (cx, cy) = (cx as i64, cy as i64);
+        let mut x = radius as i64;
+        if !self.near_clip(cx, cy, x) {
+            return;
+        }
+        let mut y = 0;
+        let mut error = 1 - x;
+        while x >= y {
+            self.wide_span(cx - x, cx + x, cy + y, colour);
+            self.wide_span(cx - x, cx + x, cy - y, colour);
+            self.wide_span(cx - y, cx + y, cy + x, colour);
+            self.wide_span(cx - y, cx + y, cy - x, colour);
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    ///Draws a sprite with its top left corner at x, y. Solid pixels are copied, invisible ones
+    ///skipped and anything in between mixed with what is already there
+    pub fn blit(&mut self, x: i32, y: i32, sprite: &Sprite) {
+        for sy in 0..sprite.height {
+            for sx in 0..sprite.width {
+                let (px, py) = (x + sx as i32, y + sy as i32);
+                if !self.in_clip(px, py) {
+                    continue;
+                }
+                let colour = sprite.pixel(sx, sy);
+                match colour.a {
+                    0 => {}
+                    255 => self.set_pixel(px, py, colour),
+                    _ => {
+                        let under = self.pixel(px, py).unwrap_or(BLACK);
+                        self.set_pixel(px, py, colour.blend_over(under));
+                    }
+                }
+            }
+        }
+    }
+
+    ///Draws one character of the 8x8 font. With no background only the character's own pixels
+    ///are drawn
+    pub fn glyph(&mut self, x: i32, y: i32, c: u8, fg: Colour, bg: Option<Colour>) {
+        for (dy, bits) in font::glyph(c).iter().enumerate() {
+            for dx in 0..font::WIDTH {
+                let (px, py) = (x + dx as i32, y + dy as i32);
+                match (*bits >> dx & 1, bg) {
+                    (1, _) => self.set_pixel(px, py, fg),
+                    (_, Some(bg)) => self.set_pixel(px, py, bg),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    ///Draws a line of text with a transparent background, anything outside printable ASCII is
+    ///drawn as a question mark
+    pub fn text(&mut self, x: i32, y: i32, text: &str, colour: Colour) {
+        for (i, c) in text.chars().enumerate() {
+            let c = match c as u32 {
+                0...0x7F => c as u8,
+                _ => b'?',
+            };
+            self.glyph(x + (i as u32 * font::WIDTH) as i32, y, c, colour, None);
+        }
+    }
+
+    ///Moves the pixel rows from top up to bottom (not included) up by some rows, filling the
+    ///rows left at the bottom. Ignores the clip rectangle, it is for scrolling text consoles
+    pub fn scroll_up(&mut self, top: u32, bottom: u32, rows: u32, fill: Colour) {
+        let bottom = bottom.min(self.height);
+        if top >= bottom {
+            return;
+        }
+        let rows = rows.min(bottom - top);
+        let shift = rows as usize * self.pitch;
+        let start = top as usize * self.pitch;
+        let end = bottom as usize * self.pitch;
+        //One copy for the whole block, it is a memmove so the rows can overlap where they land
+        unsafe {
+            let base = self.buffer.as_mut_ptr();
+            ptr::copy(base.add(start + shift), base.add(start), end - start - shift);
+        }
+        let value = self.pack(fill);
+        for row in (bottom - rows) as i32..bottom as i32 {
+            for column in 0..self.width as i32 {
+                self.write(column, row, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u32 = 21;
+
+    ///A cleared Argb8888 canvas over the buffer, SIZE pixels square
+    fn canvas(buffer: &mut [u8]) -> Canvas {
+        let mut canvas = Canvas::new(buffer, SIZE, SIZE, SIZE as usize * 4, PixelFormat::Argb8888);
+        canvas.clear(BLACK);
+        canvas
+    }
+
+    fn buffer() -> Vec<u8> {
+        vec![0; (SIZE * SIZE * 4) as usize]
+    }
+
+    ///Every pixel that isn't black
+    fn lit(canvas: &Canvas) -> Vec<(i32, i32)> {
+        let mut points = Vec::new();
+        for y in 0..canvas.height() as i32 {
+            for x in 0..canvas.width() as i32 {
+                if canvas.pixel(x, y) != Some(BLACK) {
+                    points.push((x, y));
+                }
+            }
+        }
+        points
+    }
+
+    #[test]
+    fn shallow_line_has_one_pixel_per_column() {
+        let mut buffer = buffer();
+        let mut canvas = canvas(&mut buffer);
+        canvas.line(1, 2, 15, 7, WHITE);
+        let points = lit(&canvas);
+        assert!(points.contains(&(1, 2)) && points.contains(&(15, 7)));
+        assert_eq!(points.len(), 15);
+        for x in 1..16 {
+            assert_eq!(points.iter().filter(|p| p.0 == x).count(), 1);
+        }
+    }
+
+    #[test]
+    fn steep_line_has_one_pixel_per_row() {
+        let mut buffer = buffer();
+        let mut canvas = canvas(&mut buffer);
+        canvas.line(9, 18, 4, 1, WHITE);
+        let points = lit(&canvas);
+        assert!(points.contains(&(9, 18)) && points.contains(&(4, 1)));
+        assert_eq!(points.len(), 18);
+        for y in 1..19 {
+            assert_eq!(points.iter().filter(|p| p.1 == y).count(), 1);
+        }
+    }
+
+    #[test]
+    fn lines_in_every_direction_reach_their_ends() {
+        let ends = [(20, 10), (20, 20), (10, 20), (0, 20), (0, 10), (0, 0), (10, 0), (20, 0)];
+        for &(x, y) in ends.iter() {
+            let mut buffer = buffer();
+            let mut canvas = canvas(&mut buffer);
+            canvas.line(10, 10, x, y, WHITE);
+            let points = lit(&canvas);
+            assert!(points.contains(&(10, 10)) && points.contains(&(x, y)));
+            assert_eq!(points.len(), 11);
+        }
+    }
+
+    #[test]
+    fn rect_is_an_outline_and_fill_rect_is_solid() {
+        let mut buffer = buffer();
+        let mut canvas = canvas(&mut buffer);
+        canvas.rect(2, 3, 5, 4, WHITE);
+        let points = lit(&canvas);
+        assert_eq!(points.len(), 14);
+        assert!(points.contains(&(2, 3)) && points.contains(&(6, 6)));
+        assert!(!points.contains(&(3, 4)));
+
+        canvas.clear(BLACK);
+        canvas.fill_rect(2, 3, 5, 4, WHITE);
+        let points = lit(&canvas);
+        assert_eq!(points.len(), 20);
+        assert!(points.contains(&(3, 4)));
+        assert!(!points.contains(&(7, 3)) && !points.contains(&(2, 7)));
+    }
+
+    #[test]
+    fn shapes_off_every_edge_are_clipped() {
+        let mut buffer = buffer();
+        let mut canvas = canvas(&mut buffer);
+        let edge = SIZE as i32 - 1;
+        canvas.fill_rect(-5, -5, 7, 7, WHITE);
+        canvas.fill_rect(edge - 1, edge - 1, 10, 10, WHITE);
+        assert_eq!(lit(&canvas).len(), 8);
+
+        canvas.clear(BLACK);
+        canvas.line(-10, 4, 40, 4, WHITE);
+        canvas.line(4, -10, 4, 40, WHITE);
+        assert_eq!(lit(&canvas).len(), 2 * SIZE as usize - 1);
+
+        canvas.clear(BLACK);
+        canvas.circle(0, 0, 5, WHITE);
+        canvas.fill_circle(edge, edge, 30, WHITE);
+        canvas.rect(-3, 8, 40, 3, WHITE);
+        let sprite_pixels = [255; 4 * 4 * 4];
+        canvas.blit(-2, edge - 1, &Sprite::new(4, 4, &sprite_pixels));
+        assert_eq!(canvas.pixel(-1, 0), None);
+        assert_eq!(canvas.pixel(0, SIZE as i32), None);
+    }
+
+    #[test]
+    fn clip_rectangle_limits_drawing() {
+        let mut buffer = buffer();
+        let mut canvas = canvas(&mut buffer);
+        canvas.set_clip(2, 2, 3, 3);
+        canvas.clear(WHITE);
+        canvas.line(0, 0, 20, 20, WHITE);
+        let points = lit(&canvas);
+        assert_eq!(points.len(), 9);
+        assert!(points.iter().all(|&(x, y)| x >= 2 && x < 5 && y >= 2 && y < 5));
+
+        canvas.reset_clip();
+        canvas.set_pixel(0, 0, WHITE);
+        assert_eq!(canvas.pixel(0, 0), Some(WHITE));
+    }
+
+    #[test]
+    fn clipping_a_line_leaves_its_pixels_where_they_were() {
+        let lines = [(-30, -7, 50, 40), (45, 3, -12, 17), (3, 60, 15, -40), (19, -2, 1, 23)];
+        for &(x0, y0, x1, y1) in lines.iter() {
+            let mut buffer = buffer();
+            let mut canvas = canvas(&mut buffer);
+            canvas.line(x0, y0, x1, y1, WHITE);
+            let inside = |&&(x, y): &&(i32, i32)| x >= 4 && x < 15 && y >= 6 && y < 13;
+            let whole: Vec<_> = lit(&canvas).iter().filter(inside).cloned().collect();
+            canvas.clear(BLACK);
+            canvas.set_clip(4, 6, 11, 7);
+            canvas.line(x0, y0, x1, y1, WHITE);
+            assert_eq!(lit(&canvas), whole);
+        }
+    }
+
+    #[test]
+    fn far_apart_ends_do_not_overflow() {
+        let mut buffer = buffer();
+        let mut canvas = canvas(&mut buffer);
+        canvas.line(-2_000_000_000, 0, 2_000_000_000, 0, WHITE);
+        canvas.line(i32::MIN, i32::MIN, i32::MAX, i32::MAX, WHITE);
+        let points = lit(&canvas);
+        assert_eq!(points.len(), 2 * SIZE as usize - 1);
+        assert!(points.contains(&(SIZE as i32 - 1, 0)) && points.contains(&(5, 5)));
+
+        canvas.clear(BLACK);
+        canvas.circle(i32::MAX, i32::MIN, 5, WHITE);
+        canvas.fill_circle(i32::MIN, i32::MAX, 5, WHITE);
+        canvas.fill_circle(i32::MAX, 0, 1_000_000_000, WHITE);
+        assert!(lit(&canvas).is_empty());
+    }
+
+    #[test]
+    fn circles_are_symmetrical() {
+        let centre = SIZE as i32 / 2;
+        for &filled in [false, true].iter() {
+            let mut buffer = buffer();
+            let mut canvas = canvas(&mut buffer);
+            match filled {
+                false => canvas.circle(centre, centre, 7, WHITE),
+                true => canvas.fill_circle(centre, centre, 7, WHITE),
+            }
+            let points = lit(&canvas);
+            for &(x, y) in points.iter() {
+                let (dx, dy) = (x - centre, y - centre);
+                for &(mx, my) in [(-dx, dy), (dx, -dy), (dy, dx)].iter() {
+                    assert!(points.contains(&(centre + mx, centre + my)));
+                }
+            }
+            assert!(points.contains(&(centre + 7, centre)) && points.contains(&(centre, centre - 7)));
+            assert!(!points.contains(&(centre + 8, centre)));
+            assert_eq!(points.contains(&(centre, centre)), filled);
+        }
+    }
+
+    #[test]
+    fn blit_mixes_by_alpha() {
+        let mut buffer = buffer();
+        let mut canvas = canvas(&mut buffer);
+        canvas.clear(WHITE);
+        let pixels = [255, 0, 0, 0, 255, 0, 0, 128, 255, 0, 0, 255];
+        canvas.blit(1, 1, &Sprite::new(3, 1, &pixels));
+        assert_eq!(canvas.pixel(1, 1), Some(WHITE));
+        assert_eq!(canvas.pixel(2, 1), Some(Colour::rgb(255, 127, 127)));
+        assert_eq!(canvas.pixel(3, 1), Some(RED));
+    }
+
+    #[test]
+    fn pixels_are_stored_little_endian() {
+        let mut buffer = [0; 4];
+        Canvas::new(&mut buffer, 1, 1, 4, PixelFormat::Argb8888).set_pixel(0, 0, RED);
+        assert_eq!(buffer, [0x00, 0x00, 0xFF, 0xFF]);
+        let mut buffer = [0; 3];
+        Canvas::new(&mut buffer, 1, 1, 3, PixelFormat::Rgb888).set_pixel(0, 0, BLUE);
+        assert_eq!(buffer, [0xFF, 0x00, 0x00]);
+        let mut buffer = [0; 2];
+        Canvas::new(&mut buffer, 1, 1, 2, PixelFormat::Rgb565).set_pixel(0, 0, RED);
+        assert_eq!(buffer, [0x00, 0xF8]);
+    }
+
+    #[test]
+    fn pack_and_unpack_round_trip() {
+        let colours = [
+            BLACK,
+            WHITE,
+            Colour::rgba(0x12, 0x34, 0x56, 0x78),
+            Colour::rgb(0xFE, 0x01, 0x80),
+        ];
+        for colour in colours.iter() {
+            let format = PixelFormat::Argb8888;
+            assert_eq!(format.unpack(format.pack(*colour)), *colour);
+            let format = PixelFormat::Rgb888;
+            let solid = Colour { a: 255, ..*colour };
+            assert_eq!(format.unpack(format.pack(*colour)), solid);
+        }
+        //565 loses the low bits of each colour, but every stored value comes back the same
+        let format = PixelFormat::Rgb565;
+        for value in 0..0x10000 {
+            assert_eq!(format.pack(format.unpack(value)), value);
+        }
+        assert_eq!(format.unpack(format.pack(WHITE)), WHITE);
+        assert_eq!(format.unpack(format.pack(BLACK)), BLACK);
+    }
+
+    #[test]
+    fn bgr_order_swaps_red_and_blue() {
+        let mut buffer = [0; 4];
+        {
+            let mut canvas = Canvas::new(&mut buffer, 1, 1, 4, PixelFormat::Argb8888)
+                .with_order(ColourOrder::Bgr);
+            canvas.set_pixel(0, 0, RED);
+            assert_eq!(canvas.pixel(0, 0), Some(RED));
+        }
+        assert_eq!(buffer, [0xFF, 0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn scroll_up_only_moves_the_region() {
+        let mut buffer = buffer();
+        let mut canvas = canvas(&mut buffer);
+        for y in 0..SIZE as i32 {
+            canvas.fill_rect(0, y, SIZE, 1, Colour::rgb(y as u8 + 1, 0, 0));
+        }
+        canvas.scroll_up(2, 10, 3, BLUE);
+        for y in 0..SIZE as i32 {
+            let expected = match y {
+                2...6 => Colour::rgb(y as u8 + 4, 0, 0),
+                7...9 => BLUE,
+                _ => Colour::rgb(y as u8 + 1, 0, 0),
+            };
+            for x in 0..SIZE as i32 {
+                assert_eq!(canvas.pixel(x, y), Some(expected), "pixel {}, {}", x, y);
+            }
+        }
+        //Scrolling by the whole region or more just fills it
+        canvas.scroll_up(0, SIZE, SIZE + 5, GREEN);
+        assert!((0..SIZE as i32).all(|y| canvas.pixel(3, y) == Some(GREEN)));
+    }
+
+    #[test]
+    fn padding_past_the_width_is_left_alone() {
+        let mut buffer = [0xAA; 2 * 12];
+        Canvas::new(&mut buffer, 2, 2, 12, PixelFormat::Argb8888).clear(WHITE);
+        assert!(buffer[8..12].iter().all(|&b| b == 0xAA));
+        assert!(buffer[20..24].iter().all(|&b| b == 0xAA));
+        assert!(buffer[..8].iter().all(|&b| b == 0xFF));
+    }
+}
@@ -0,0 +1,92 @@
+/// The general purpose clock manager, which makes the clocks for peripherals like the PWM that
+/// don't run straight off the core clock.
+///
+/// The Broadcom manual only documents the GPIO clocks on page 105, the peripheral clocks work the
+/// same way with their control and divisor registers at other offsets. The PWM clock is at 0xA0.
+
+use common::CLOCK_MANAGER_BASE;
+use volatile::Volatile;
+
+///Every write to a clock manager register has to carry this in the top byte or it is ignored
+const PASSWORD: u32 = 0x5A00_0000;
+
+///CTL bits
+const CTL_BUSY: u32 = 1 << 7;
+const CTL_ENAB: u32 = 1 << 4;
+
+///Frequency of the crystal oscillator in Hz
+pub const OSCILLATOR_HZ: u32 = 19_200_000;
+
+///Control and divisor registers for one clock, see page 107 of the Broadcom manual
+///
+///This structure uses [repr(C)].
+///This means that the compiler is NOT free to reorder the fields of this structure for alignment
+///reasons, its very important the layout is exactly as ive defined it.
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    CTL: Volatile<u32>,
+    ///Integer part of the divisor in bits 23:12, fractional part in bits 11:0
+    DIV: Volatile<u32>,
+}
+
+///The clocks this module can drive, the value is the offset of the clock's CTL register
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Clock {
+    Pwm = 0xA0,
+}
+
+///What a clock is divided down from. Only the oscillator is the same speed on every board and
+///isn't changed by the firmware, the PLLs are used by other things which can retune them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    ///19.2MHz crystal oscillator
+    Oscillator = 1,
+    ///500MHz PLLD
+    PllD = 6,
+}
+
+impl Source {
+    pub fn frequency(&self) -> u32 {
+        match *self {
+            Source::Oscillator => OSCILLATOR_HZ,
+            Source::PllD => 500_000_000,
+        }
+    }
+}
+
+///Reasons a clock can't be started
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockError {
+    ///The integer divisor has to be between 2 and 4095
+    DivisorOutOfRange,
+}
+
+fn registers(clock: Clock) -> &'static mut Registers {
+    unsafe { &mut *((CLOCK_MANAGER_BASE + clock as usize) as *mut Registers) }
+}
+
+///Stops the clock and waits for it to actually stop, changing the source or divisor of a
+///running clock can glitch it or lock it up
+pub fn stop(clock: Clock) {
+    let registers = registers(clock);
+    let ctl = registers.CTL.read() & 0x00FF_FFFF;
+    registers.CTL.write(PASSWORD | (ctl & !CTL_ENAB));
+    while registers.CTL.read() & CTL_BUSY != 0 {}
+}
+
+///Starts the clock at source frequency / divisor, returning the frequency in Hz.
+///Only whole divisors are used, fractional ones need the MASH noise shaper which adds jitter
+pub fn start(clock: Clock, source: Source, divisor: u32) -> Result<u32, ClockError> {
+    if divisor < 2 || divisor > 0xFFF {
+        return Err(ClockError::DivisorOutOfRange);
+    }
+    stop(clock);
+    let registers = registers(clock);
+    registers.DIV.write(PASSWORD | (divisor << 12));
+    //The source is set first and the clock enabled in a separate write, page 107
+    registers.CTL.write(PASSWORD | source as u32);
+    registers.CTL.write(PASSWORD | source as u32 | CTL_ENAB);
+    while registers.CTL.read() & CTL_BUSY == 0 {}
+    Ok(source.frequency() / divisor)
+}
@@ -3,3 +3,17 @@ pub const IO_BASE: usize = 0x3F000000;
 pub const TIMER_BASE: usize = IO_BASE + 0x3000;
 pub const MU_REG_BASE: usize = IO_BASE + 0x215040;
 pub const AUX_ENABLES: usize = IO_BASE + 0x215004;
+pub const INTERRUPT_BASE: usize = IO_BASE + 0xB200;
+pub const LOCAL_BASE: usize = 0x40000000;
+pub const MAILBOX_BASE: usize = IO_BASE + 0xB880;
+pub const UART0_BASE: usize = IO_BASE + 0x201000;
+pub const CLOCK_MANAGER_BASE: usize = IO_BASE + 0x101000;
+pub const PWM_BASE: usize = IO_BASE + 0x20C000;
+pub const BSC1_BASE: usize = IO_BASE + 0x804000;
+pub const SPI0_BASE: usize = IO_BASE + 0x204000;
+pub const DMA_BASE: usize = IO_BASE + 0x7000;
+
+///Peripherals appear at 0x7E000000 on the VideoCore bus that the DMA engines sit on
+pub const BUS_IO_BASE: usize = 0x7E000000;
+///The uncached alias of RAM on the VideoCore bus
+pub const BUS_RAM_ALIAS: usize = 0xC0000000;
@@ -0,0 +1,150 @@
+/// Documentation for the DMA controller can be found on page 38 of the Broadcom manual.
+///
+/// Each channel works through a linked list of control blocks in memory, copying TXFR_LEN bytes
+/// from the source address to the destination for each one. Addresses are VideoCore bus
+/// addresses, not the ARM ones: peripherals are at 0x7E000000 and RAM is used through its
+/// uncached alias at 0xC0000000. The kernel runs with the caches off so nothing needs flushing.
+///
+/// Only what the drivers need is here, single control blocks paced by a peripheral's DREQ line.
+
+use common::{BUS_IO_BASE, BUS_RAM_ALIAS, DMA_BASE, IO_BASE};
+use volatile::Volatile;
+
+///Registers of one channel, each channel is 0x100 further on, page 40
+///
+///This structure uses [repr(C)].
+///This means that the compiler is NOT free to reorder the fields of this structure for alignment
+///reasons, its very important the layout is exactly as ive defined it.
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    ///Control and status
+    CS: Volatile<u32>,
+    ///Bus address of the control block to start from
+    CONBLK_AD: Volatile<u32>,
+    ///The rest are copies of the current control block
+    TI: Volatile<u32>,
+    SOURCE_AD: Volatile<u32>,
+    DEST_AD: Volatile<u32>,
+    TXFR_LEN: Volatile<u32>,
+    STRIDE: Volatile<u32>,
+    NEXTCONBK: Volatile<u32>,
+    DEBUG: Volatile<u32>,
+}
+
+///Enable bits for every channel, page 47
+const ENABLE: usize = DMA_BASE + 0xFF0;
+
+///CS bits
+const CS_RESET: u32 = 1 << 31;
+const CS_ERROR: u32 = 1 << 8;
+const CS_INT: u32 = 1 << 2;
+const CS_END: u32 = 1 << 1;
+const CS_ACTIVE: u32 = 1 << 0;
+
+///TI bits, page 50
+pub const TI_WAIT_RESP: u32 = 1 << 3;
+pub const TI_DEST_INC: u32 = 1 << 4;
+pub const TI_DEST_DREQ: u32 = 1 << 6;
+pub const TI_SRC_INC: u32 = 1 << 8;
+pub const TI_SRC_DREQ: u32 = 1 << 10;
+
+///Which peripheral's DREQ paces the transfer, goes in the PERMAP field of TI
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dreq {
+    SpiTx = 6,
+    SpiRx = 7,
+}
+
+impl Dreq {
+    ///The TI bits to select this DREQ
+    pub fn permap(&self) -> u32 {
+        (*self as u32) << 16
+    }
+}
+
+///One step of a transfer, read by the DMA engine straight from memory so it has to be 32 byte
+///aligned, page 40
+#[repr(C, align(32))]
+#[derive(Clone, Copy)]
+pub struct ControlBlock {
+    pub ti: u32,
+    pub source: u32,
+    pub dest: u32,
+    pub length: u32,
+    pub stride: u32,
+    pub next: u32,
+    _reserved: [u32; 2],
+}
+
+impl ControlBlock {
+    pub const fn new() -> ControlBlock {
+        ControlBlock {
+            ti: 0,
+            source: 0,
+            dest: 0,
+            length: 0,
+            stride: 0,
+            next: 0,
+            _reserved: [0; 2],
+        }
+    }
+}
+
+///Bus address of something in RAM
+pub fn bus_address<T>(p: *const T) -> u32 {
+    (p as usize | BUS_RAM_ALIAS) as u32
+}
+
+///Bus address of a peripheral register given its ARM address
+pub fn bus_io_address(address: usize) -> u32 {
+    (address - IO_BASE + BUS_IO_BASE) as u32
+}
+
+///A DMA channel. Channels 0, 1, 3, 6 and 7 are used by the firmware on the Pi 3, anything else
+///below 15 is free
+pub struct Channel {
+    registers: &'static mut Registers,
+    number: u8,
+}
+
+impl Channel {
+    ///Constructor, turns the channel on and resets it
+    pub fn new(number: u8) -> Channel {
+        if number > 14 {
+            panic!("DMA channel {} doesn't exist", number)
+        }
+        let enable = unsafe { &mut *(ENABLE as *mut Volatile<u32>) };
+        enable.write(enable.read() | 1 << number);
+        let registers = unsafe { &mut *((DMA_BASE + number as usize * 0x100) as *mut Registers) };
+        registers.CS.write(CS_RESET);
+        Channel { registers, number }
+    }
+
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    ///Starts working through the control blocks. The block has to stay where it is until
+    ///is_done says the channel has finished with it
+    pub fn start(&mut self, block: &ControlBlock) {
+        self.registers.CS.write(CS_END | CS_INT);
+        self.registers.CONBLK_AD.write(bus_address(block));
+        self.registers.CS.write(CS_ACTIVE);
+    }
+
+    ///Checks if the channel has got to the end of its control blocks
+    pub fn is_done(&self) -> bool {
+        self.registers.CS.read() & CS_ACTIVE == 0
+    }
+
+    ///Checks if the channel stopped because of a bus error
+    pub fn has_error(&self) -> bool {
+        self.registers.CS.read() & CS_ERROR != 0
+    }
+
+    ///Stops the channel wherever it has got to
+    pub fn abort(&mut self) {
+        self.registers.CS.write(CS_RESET);
+    }
+}
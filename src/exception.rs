@@ -0,0 +1,217 @@
+/// Rust side of the exception vector table in ext/vectors.S
+///
+/// Documentation for the exception model can be found in chapter D1 of the ARMv8 Architecture
+/// Reference Manual, the syndrome register encodings are in section D7.2.28 (ESR_EL1)
+use core::fmt;
+use interrupt;
+
+///Where the exception was taken from, in the same order as the groups in the vector table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    CurrentSpEl0,
+    CurrentSpElx,
+    LowerAArch64,
+    LowerAArch32,
+}
+
+///What type of exception was taken, in the same order as the entries in each group
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Synchronous,
+    Irq,
+    Fiq,
+    SError,
+}
+
+///Decoded vector table entry index passed in by the assembly stub
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Info {
+    pub source: Source,
+    pub kind: Kind,
+}
+
+impl From<u64> for Info {
+    fn from(index: u64) -> Info {
+        //The table is 4 groups of 4, so the upper 2 bits are the source and the lower 2 the kind
+        let source = match (index >> 2) & 0b11 {
+            0 => Source::CurrentSpEl0,
+            1 => Source::CurrentSpElx,
+            2 => Source::LowerAArch64,
+            _ => Source::LowerAArch32,
+        };
+        let kind = match index & 0b11 {
+            0 => Kind::Synchronous,
+            1 => Kind::Irq,
+            2 => Kind::Fiq,
+            _ => Kind::SError,
+        };
+        Info { source, kind }
+    }
+}
+
+///This structure uses [repr(C)] and is built on the stack by __trap_entry in ext/vectors.S,
+///the field order has to match the order the registers are stored in exactly.
+#[repr(C)]
+pub struct TrapFrame {
+    ///General purpose registers x0 -> x30 (x30 is the link register)
+    pub x: [u64; 31],
+    ///Exception Link Register, the address execution returns to with eret
+    pub elr: u64,
+    ///Saved Program Status Register, the PSTATE from before the exception
+    pub spsr: u64,
+    ///Exception Syndrome Register, why the exception happened
+    pub esr: u64,
+    ///Fault Address Register, the faulting virtual address for aborts
+    pub far: u64,
+    ///Padding to keep the stack 16 byte aligned
+    _reserved: u64,
+}
+
+///Prints every saved register, 3 to a line so it fits on a terminal
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, reg) in self.x.iter().enumerate() {
+            write!(f, "x{:<2} {:#018x}", i, reg)?;
+            match i % 3 {
+                2 => write!(f, "\r\n")?,
+                _ => write!(f, "  ")?,
+            }
+        }
+        write!(f, "\r\nELR  {:#018x}  SPSR {:#018x}\r\n", self.elr, self.spsr)?;
+        write!(f, "ESR  {:#018x}  FAR  {:#018x}\r\n", self.esr, self.far)
+    }
+}
+
+///Exception classes, bits [31:26] of ESR_EL1. Only the ones we can actually hit are named.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExceptionClass {
+    Unknown,
+    WfiWfe,
+    SimdFp,
+    IllegalExecutionState,
+    Svc,
+    Hvc,
+    Smc,
+    MsrMrs,
+    InstructionAbortLower,
+    InstructionAbortCurrent,
+    PcAlignment,
+    DataAbortLower,
+    DataAbortCurrent,
+    SpAlignment,
+    SError,
+    BreakpointLower,
+    BreakpointCurrent,
+    StepLower,
+    StepCurrent,
+    WatchpointLower,
+    WatchpointCurrent,
+    Brk,
+    Other(u8),
+}
+
+impl ExceptionClass {
+    ///Reads the exception class out of a raw ESR_EL1 value
+    pub fn from_esr(esr: u64) -> ExceptionClass {
+        match ((esr >> 26) & 0b111111) as u8 {
+            0x00 => ExceptionClass::Unknown,
+            0x01 => ExceptionClass::WfiWfe,
+            0x07 => ExceptionClass::SimdFp,
+            0x0E => ExceptionClass::IllegalExecutionState,
+            0x15 => ExceptionClass::Svc,
+            0x16 => ExceptionClass::Hvc,
+            0x17 => ExceptionClass::Smc,
+            0x18 => ExceptionClass::MsrMrs,
+            0x20 => ExceptionClass::InstructionAbortLower,
+            0x21 => ExceptionClass::InstructionAbortCurrent,
+            0x22 => ExceptionClass::PcAlignment,
+            0x24 => ExceptionClass::DataAbortLower,
+            0x25 => ExceptionClass::DataAbortCurrent,
+            0x26 => ExceptionClass::SpAlignment,
+            0x2F => ExceptionClass::SError,
+            0x30 => ExceptionClass::BreakpointLower,
+            0x31 => ExceptionClass::BreakpointCurrent,
+            0x32 => ExceptionClass::StepLower,
+            0x33 => ExceptionClass::StepCurrent,
+            0x34 => ExceptionClass::WatchpointLower,
+            0x35 => ExceptionClass::WatchpointCurrent,
+            0x3C => ExceptionClass::Brk,
+            n @ _ => ExceptionClass::Other(n),
+        }
+    }
+
+    ///True for instruction and data aborts, where FAR_EL1 holds the faulting address
+    pub fn is_abort(&self) -> bool {
+        match *self {
+            ExceptionClass::InstructionAbortLower
+            | ExceptionClass::InstructionAbortCurrent
+            | ExceptionClass::DataAbortLower
+            | ExceptionClass::DataAbortCurrent
+            | ExceptionClass::PcAlignment
+            | ExceptionClass::WatchpointLower
+            | ExceptionClass::WatchpointCurrent => true,
+            _ => false,
+        }
+    }
+}
+
+///Instruction/Data Fault Status Code, bits [5:0] of the ISS for aborts.
+///The level is the translation table level the fault happened at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultStatus {
+    AddressSize(u8),
+    Translation(u8),
+    AccessFlag(u8),
+    Permission(u8),
+    SynchronousExternal,
+    Alignment,
+    TlbConflict,
+    Other(u8),
+}
+
+impl FaultStatus {
+    ///Reads the fault status code out of a raw ESR_EL1 value
+    pub fn from_esr(esr: u64) -> FaultStatus {
+        let code = (esr & 0b111111) as u8;
+        let level = code & 0b11;
+        match code >> 2 {
+            0b0000 => FaultStatus::AddressSize(level),
+            0b0001 => FaultStatus::Translation(level),
+            0b0010 => FaultStatus::AccessFlag(level),
+            0b0011 => FaultStatus::Permission(level),
+            _ => match code {
+                0b010000 => FaultStatus::SynchronousExternal,
+                0b100001 => FaultStatus::Alignment,
+                0b110000 => FaultStatus::TlbConflict,
+                _ => FaultStatus::Other(code),
+            },
+        }
+    }
+}
+
+///Entry point from __trap_entry in ext/vectors.S.
+///
+///The index is the number of the vector table entry that was taken and the trap frame is the
+///register state saved on the stack, any changes made to it are restored before eret.
+#[cfg_attr(not(test), no_mangle)]
+pub extern "C" fn handle_exception(index: u64, tf: &mut TrapFrame) {
+    let info = Info::from(index);
+    match info.kind {
+        Kind::Synchronous => {
+            let class = ExceptionClass::from_esr(tf.esr);
+            if class.is_abort() {
+                panic!(
+                    "{:?} from {:?}: {:?}\r\nFaulting address: {:#x}\r\n{}",
+                    class,
+                    info.source,
+                    FaultStatus::from_esr(tf.esr),
+                    tf.far,
+                    tf
+                );
+            }
+            panic!("{:?} from {:?}\r\n{}", class, info.source, tf);
+        }
+        Kind::Irq => interrupt::dispatch(),
+        _ => panic!("Unhandled {:?} from {:?}\r\n{}", info.kind, info.source, tf),
+    }
+}
@@ -0,0 +1,728 @@
+/// A linear framebuffer allocated by the GPU firmware, and a text console drawn on it.
+///
+/// The firmware is asked through the mailbox for a buffer of a given resolution and depth, it
+/// then scans that memory out to HDMI (or QEMU's display window) by itself so drawing is just
+/// writing pixels. Rows of pixels are pitch bytes apart, which the firmware chooses and can be
+/// more than the width times the bytes per pixel.
+///
+/// The buffer can be given a virtual size bigger than the display, the virtual offset then picks
+/// which part of it is shown. A virtual height that is a multiple of the display height splits it
+/// into pages, one is shown while the next is drawn on and flip swaps them over.
+
+use canvas::{Canvas, Colour, PixelFormat};
+use core::{fmt, slice};
+use font;
+use mailbox::{self, AllocateBuffer, ColourOrder, Depth, DisplaySize, MailboxError, Pitch,
+              PixelOrder, VirtualOffset};
+
+///The firmware hands out VideoCore bus addresses, clearing the top two bits gives the ARM
+///physical address of the same memory
+const BUS_ADDRESS_MASK: u32 = 0x3FFF_FFFF;
+
+///Settings to ask the firmware for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FramebufferConfig {
+    width: u32,
+    height: u32,
+    virtual_width: u32,
+    virtual_height: u32,
+    depth: u32,
+}
+
+impl FramebufferConfig {
+    ///Constructor, 32 bits per pixel with the virtual size the same as the display
+    pub fn new(width: u32, height: u32) -> FramebufferConfig {
+        FramebufferConfig {
+            width,
+            height,
+            virtual_width: width,
+            virtual_height: height,
+            depth: 32,
+        }
+    }
+    ///Set the bits per pixel, 16, 24 and 32 are supported
+    pub fn depth(mut self, depth: u32) -> Self {
+        self.depth = depth;
+        self
+    }
+    ///Set the size of the buffer in memory. Twice the display height leaves room for a second
+    ///page to draw on while the first is shown
+    pub fn virtual_size(mut self, width: u32, height: u32) -> Self {
+        self.virtual_width = width;
+        self.virtual_height = height;
+        self
+    }
+}
+
+///Reasons the framebuffer can't be set up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FramebufferError {
+    ///The mailbox request failed
+    Mailbox(MailboxError),
+    ///The firmware chose a depth this driver can't draw in, 8 bit palette mode for example
+    UnsupportedDepth(u32),
+    ///The firmware didn't hand out a buffer, or one too small for the size it reported
+    NoBuffer,
+    ///The display is too small to hold a single character of the console
+    TooSmall { width: u32, height: u32 },
+}
+
+impl From<MailboxError> for FramebufferError {
+    fn from(e: MailboxError) -> FramebufferError {
+        FramebufferError::Mailbox(e)
+    }
+}
+
+impl fmt::Display for FramebufferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FramebufferError::Mailbox(e) => write!(f, "mailbox: {}", e),
+            FramebufferError::UnsupportedDepth(depth) => {
+                write!(f, "{} bits per pixel not supported", depth)
+            }
+            FramebufferError::NoBuffer => write!(f, "the firmware didn't allocate a buffer"),
+            FramebufferError::TooSmall { width, height } => {
+                write!(f, "a {}x{} display can't fit a character", width, height)
+            }
+        }
+    }
+}
+
+///The framebuffer, owned by whoever is drawing on it
+pub struct Framebuffer {
+    buffer: &'static mut [u8],
+    width: u32,
+    height: u32,
+    virtual_width: u32,
+    virtual_height: u32,
+    pitch: u32,
+    format: PixelFormat,
+    order: ColourOrder,
+    offset: (u32, u32),
+}
+
+impl Framebuffer {
+    ///Asks the firmware for a framebuffer. Everything goes in one request, the firmware sets the
+    ///size, depth and order first and then allocates the buffer to suit. It may not give exactly
+    ///what was asked for, the getters say what it did give
+    pub fn new(config: &FramebufferConfig) -> Result<Framebuffer, FramebufferError> {
+        let mut physical = DisplaySize::physical(config.width, config.height);
+        let mut virtual_size = DisplaySize::virtual_size(config.virtual_width, config.virtual_height);
+        let mut depth = Depth::new(config.depth);
+        let mut order = PixelOrder::new(ColourOrder::Rgb);
+        let mut offset = VirtualOffset::new(0, 0);
+        let mut allocate = AllocateBuffer::new(16);
+        let mut pitch = Pitch::new();
+        mailbox::request(&mut [
+            &mut physical,
+            &mut virtual_size,
+            &mut depth,
+            &mut order,
+            &mut offset,
+            &mut allocate,
+            &mut pitch,
+        ])?;
+
+        let format = match PixelFormat::from_depth(depth.bits) {
+            Some(format) => format,
+            None => return Err(FramebufferError::UnsupportedDepth(depth.bits)),
+        };
+        let needed = pitch.bytes as usize * virtual_size.height as usize;
+        if allocate.base == 0 || (allocate.size as usize) < needed {
+            return Err(FramebufferError::NoBuffer);
+        }
+        let base = (allocate.base & BUS_ADDRESS_MASK) as usize as *mut u8;
+        Ok(Framebuffer {
+            buffer: unsafe { slice::from_raw_parts_mut(base, needed) },
+            width: physical.width,
+            height: physical.height,
+            virtual_width: virtual_size.width,
+            virtual_height: virtual_size.height,
+            pitch: pitch.bytes,
+            format,
+            order: order.order,
+            offset: (offset.x, offset.y),
+        })
+    }
+
+    ///Width of the display in pixels
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    ///Height of the display in pixels
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn virtual_width(&self) -> u32 {
+        self.virtual_width
+    }
+
+    pub fn virtual_height(&self) -> u32 {
+        self.virtual_height
+    }
+
+    ///Bytes from one row of pixels to the next
+    pub fn pitch(&self) -> u32 {
+        self.pitch
+    }
+
+    ///Bits per pixel
+    pub fn depth(&self) -> u32 {
+        self.format.bytes_per_pixel() as u32 * 8
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn order(&self) -> ColourOrder {
+        self.order
+    }
+
+    ///The whole buffer, every row of the virtual size
+    pub fn buffer(&mut self) -> &mut [u8] {
+        &mut self.buffer[..]
+    }
+
+    ///How many display sized pages fit in the virtual height
+    pub fn page_count(&self) -> u32 {
+        (self.virtual_height / self.height).max(1)
+    }
+
+    ///The page on the display now
+    pub fn shown_page(&self) -> u32 {
+        self.offset.1 / self.height
+    }
+
+    ///Draws on one page of the buffer, panics if there's no such page
+    pub fn page(&mut self, page: u32) -> Canvas {
+        assert!(page < self.page_count(), "no page {} in the framebuffer", page);
+        let length = self.pitch as usize * self.height as usize;
+        let start = page as usize * length;
+        Canvas::new(
+            &mut self.buffer[start..start + length],
+            self.width,
+            self.height,
+            self.pitch as usize,
+            self.format,
+        ).with_order(self.order)
+    }
+
+    ///Draws on the page that is shown
+    pub fn canvas(&mut self) -> Canvas {
+        let page = self.shown_page();
+        self.page(page)
+    }
+
+    ///Draws on the page flip will show next. With only one page this is the one shown, drawing
+    ///still works but can be seen happening
+    pub fn back_buffer(&mut self) -> Canvas {
+        let page = (self.shown_page() + 1) % self.page_count();
+        self.page(page)
+    }
+
+    ///Shows the back buffer, the old page becomes the back buffer to draw the next frame on
+    pub fn flip(&mut self) -> Result<(), MailboxError> {
+        let page = (self.shown_page() + 1) % self.page_count();
+        self.show_page(page)
+    }
+
+    ///Puts one of the pages on the display, panics if there's no such page
+    pub fn show_page(&mut self, page: u32) -> Result<(), MailboxError> {
+        assert!(page < self.page_count(), "no page {} in the framebuffer", page);
+        let y = page * self.height;
+        self.set_virtual_offset(0, y)
+    }
+
+    ///Which part of the virtual buffer is on the display, as the top left corner
+    pub fn virtual_offset(&self) -> (u32, u32) {
+        self.offset
+    }
+
+    ///Moves the part of the virtual buffer that is shown, the change happens at the next frame
+    pub fn set_virtual_offset(&mut self, x: u32, y: u32) -> Result<(), MailboxError> {
+        let mut offset = VirtualOffset::new(x, y);
+        mailbox::request_one(&mut offset)?;
+        self.offset = (offset.x, offset.y);
+        Ok(())
+    }
+}
+
+///The sixteen ANSI colours as the VGA text mode drew them, black, red, green, yellow, blue,
+///magenta, cyan and white then the bright versions of each
+const PALETTE: [Colour; 16] = [
+    Colour::rgb(0, 0, 0),
+    Colour::rgb(170, 0, 0),
+    Colour::rgb(0, 170, 0),
+    Colour::rgb(170, 85, 0),
+    Colour::rgb(0, 0, 170),
+    Colour::rgb(170, 0, 170),
+    Colour::rgb(0, 170, 170),
+    Colour::rgb(170, 170, 170),
+    Colour::rgb(85, 85, 85),
+    Colour::rgb(255, 85, 85),
+    Colour::rgb(85, 255, 85),
+    Colour::rgb(255, 255, 85),
+    Colour::rgb(85, 85, 255),
+    Colour::rgb(255, 85, 255),
+    Colour::rgb(85, 255, 255),
+    Colour::rgb(255, 255, 255),
+];
+
+///Levels of red, green and blue in the 6x6x6 colour cube of the 256 colour palette
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+///A colour from the 256 colour palette, the sixteen ANSI colours then the cube then 24 greys
+fn indexed_colour(index: u8) -> Colour {
+    match index {
+        0...15 => PALETTE[index as usize],
+        16...231 => {
+            let i = (index - 16) as usize;
+            Colour::rgb(CUBE_LEVELS[i / 36], CUBE_LEVELS[i / 6 % 6], CUBE_LEVELS[i % 6])
+        }
+        _ => {
+            let grey = 8 + (index - 232) * 10;
+            Colour::rgb(grey, grey, grey)
+        }
+    }
+}
+
+///Reads the colour after a 38 or 48 in an ESC [ m sequence, either 5 and a palette index or 2
+///and red, green and blue. Returns how many numbers it used, all of them if they don't make sense
+fn extended_colour(params: &[u32]) -> (Option<Colour>, usize) {
+    let byte = |i: usize| params[i].min(255) as u8;
+    match params.first() {
+        Some(&5) if params.len() >= 2 => (Some(indexed_colour(byte(1))), 2),
+        Some(&2) if params.len() >= 4 => (Some(Colour::rgb(byte(1), byte(2), byte(3))), 4),
+        _ => (None, params.len()),
+    }
+}
+
+///Colours the console starts with and goes back to on a reset, as PALETTE entries
+const DEFAULT_FG: usize = 7;
+const DEFAULT_BG: usize = 0;
+
+///How characters are drawn, set by ESC [ m
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Pen {
+    fg: Colour,
+    bg: Colour,
+    bold: bool,
+    underline: bool,
+    reverse: bool,
+}
+
+impl Pen {
+    fn new() -> Pen {
+        Pen {
+            fg: PALETTE[DEFAULT_FG],
+            bg: PALETTE[DEFAULT_BG],
+            bold: false,
+            underline: false,
+            reverse: false,
+        }
+    }
+}
+
+///What ESC 7 remembers for ESC 8
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Saved {
+    column: u32,
+    row: u32,
+    pen: Pen,
+}
+
+///Most numbers an escape sequence can have, 24 bit colours take five
+const MAX_PARAMS: usize = 16;
+
+///Where the console is up to in reading an escape sequence
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Escape {
+    None,
+    ///Just had the escape character
+    Escape,
+    ///Inside an ESC [ sequence, reading numbers until the letter that ends it
+    Csi,
+}
+
+///Text console on the framebuffer, drawn with the 8x8 font from the top of the display.
+///It understands the escape codes AnsiPrettyPrinter sends, so anything written to a UART can be
+///written here as well and look the same. With a second page in the framebuffer the alternate
+///screen is drawn there, so the first page is kept as it was
+pub struct FramebufferConsole {
+    framebuffer: Framebuffer,
+    columns: u32,
+    rows: u32,
+    ///Can be one past the last column after writing there, see draw_char
+    column: u32,
+    row: u32,
+    pen: Pen,
+    saved: Saved,
+    ///First and last rows that scroll, both included
+    scroll_top: u32,
+    scroll_bottom: u32,
+    alternate: bool,
+    escape: Escape,
+    ///Set by a ? at the start of an ESC [ sequence, for the DEC private modes
+    private: bool,
+    params: [u32; MAX_PARAMS],
+    param_count: usize,
+}
+
+impl FramebufferConsole {
+    ///Constructor, clears the display. Fails if the display is smaller than one character
+    pub fn new(framebuffer: Framebuffer) -> Result<FramebufferConsole, FramebufferError> {
+        let (width, height) = (framebuffer.width(), framebuffer.height());
+        let columns = width / font::WIDTH;
+        let rows = height / font::HEIGHT;
+        if columns == 0 || rows == 0 {
+            return Err(FramebufferError::TooSmall { width, height });
+        }
+        let mut console = FramebufferConsole {
+            columns,
+            rows,
+            framebuffer,
+            column: 0,
+            row: 0,
+            pen: Pen::new(),
+            saved: Saved {
+                column: 0,
+                row: 0,
+                pen: Pen::new(),
+            },
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            alternate: false,
+            escape: Escape::None,
+            private: false,
+            params: [0; MAX_PARAMS],
+            param_count: 0,
+        };
+        console.reset();
+        Ok(console)
+    }
+
+    ///Size of the console in characters
+    pub fn columns(&self) -> u32 {
+        self.columns
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.rows
+    }
+
+    ///Puts everything back how it started, back on the first screen with the default colours
+    ///and the whole display scrolling, then clears the display
+    pub fn reset(&mut self) {
+        if self.alternate && self.framebuffer.page_count() > 1 {
+            let _ = self.framebuffer.show_page(0);
+        }
+        self.alternate = false;
+        self.pen = Pen::new();
+        self.saved = Saved {
+            column: 0,
+            row: 0,
+            pen: Pen::new(),
+        };
+        self.scroll_top = 0;
+        self.scroll_bottom = self.rows - 1;
+        self.clear();
+    }
+
+    ///Clears the display to the background colour and moves the cursor to the top left
+    pub fn clear(&mut self) {
+        let bg = self.pen.bg;
+        self.framebuffer.canvas().clear(bg);
+        self.column = 0;
+        self.row = 0;
+    }
+
+    ///Writes a byte of text or part of an escape sequence
+    pub fn write_byte(&mut self, b: u8) {
+        match self.escape {
+            Escape::None => self.write_text(b),
+            Escape::Escape => self.write_escape(b),
+            Escape::Csi => self.write_csi(b),
+        }
+    }
+
+    fn write_text(&mut self, b: u8) {
+        match b {
+            0x1B => self.escape = Escape::Escape,
+            b'\r' => self.column = 0,
+            b'\n' => self.line_feed(),
+            //Backspace
+            0x08 => self.column = self.last_column().saturating_sub(1),
+            b'\t' => self.column = ((self.column / 8 + 1) * 8).min(self.columns - 1),
+            //The rest of the control characters don't draw anything
+            0x00...0x1F | 0x7F => {}
+            //Only the first byte of a multi byte UTF-8 character is drawn, as a question mark
+            0x80...0xBF => {}
+            _ => self.draw_char(b),
+        }
+    }
+
+    fn write_escape(&mut self, b: u8) {
+        self.escape = Escape::None;
+        match b {
+            b'c' => self.reset(),
+            b'7' => self.save_cursor(),
+            b'8' => self.restore_cursor(),
+            b'[' => {
+                self.escape = Escape::Csi;
+                self.private = false;
+                self.params = [0; MAX_PARAMS];
+                self.param_count = 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn write_csi(&mut self, b: u8) {
+        match b {
+            b'0'...b'9' => {
+                let param = &mut self.params[self.param_count];
+                *param = param.saturating_mul(10).saturating_add((b - b'0') as u32);
+            }
+            b';' => self.param_count = (self.param_count + 1).min(MAX_PARAMS - 1),
+            b'?' => self.private = true,
+            //A letter ends the sequence
+            0x40...0x7E => {
+                self.escape = Escape::None;
+                let count = self.param_count + 1;
+                self.run_csi(b, count);
+            }
+            _ => {}
+        }
+    }
+
+    ///Carries out an ESC [ sequence once the letter ending it arrives
+    fn run_csi(&mut self, command: u8, count: usize) {
+        //Cursor moves treat a missing or zero count as one, and positions count from one
+        let (first, second) = (self.params[0], self.params[1]);
+        let n = first.max(1);
+        if self.private {
+            match (command, first) {
+                (b'h', 1049) => self.enter_alternate_screen(),
+                (b'l', 1049) => self.leave_alternate_screen(),
+                _ => {}
+            }
+            return;
+        }
+        match command {
+            b'A' => self.row = self.row.saturating_sub(n),
+            b'B' => self.row = self.row.saturating_add(n).min(self.rows - 1),
+            b'C' => self.column = self.last_column().saturating_add(n).min(self.columns - 1),
+            b'D' => self.column = self.last_column().saturating_sub(n),
+            b'H' | b'f' => {
+                self.row = (n - 1).min(self.rows - 1);
+                self.column = (second.max(1) - 1).min(self.columns - 1);
+            }
+            b'J' => self.erase_display(first),
+            b'K' => self.erase_line(first),
+            b'm' => self.set_attributes(count),
+            b'r' => {
+                let top = n - 1;
+                let bottom = match second {
+                    0 => self.rows - 1,
+                    bottom => (bottom - 1).min(self.rows - 1),
+                };
+                if top < bottom {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                }
+                self.row = 0;
+                self.column = 0;
+            }
+            b's' => self.save_cursor(),
+            b'u' => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    ///The numbers of an ESC [ m sequence, in order
+    fn set_attributes(&mut self, count: usize) {
+        let mut i = 0;
+        while i < count {
+            match self.params[i] {
+                0 => self.pen = Pen::new(),
+                1 => self.pen.bold = true,
+                4 => self.pen.underline = true,
+                7 => self.pen.reverse = true,
+                22 => self.pen.bold = false,
+                24 => self.pen.underline = false,
+                27 => self.pen.reverse = false,
+                param @ 30...37 => self.pen.fg = PALETTE[(param - 30) as usize],
+                39 => self.pen.fg = PALETTE[DEFAULT_FG],
+                param @ 40...47 => self.pen.bg = PALETTE[(param - 40) as usize],
+                49 => self.pen.bg = PALETTE[DEFAULT_BG],
+                param @ 90...97 => self.pen.fg = PALETTE[(param - 90 + 8) as usize],
+                param @ 100...107 => self.pen.bg = PALETTE[(param - 100 + 8) as usize],
+                param @ 38 | param @ 48 => {
+                    let (colour, used) = extended_colour(&self.params[i + 1..count]);
+                    match (param, colour) {
+                        (38, Some(colour)) => self.pen.fg = colour,
+                        (_, Some(colour)) => self.pen.bg = colour,
+                        _ => {}
+                    }
+                    i += used;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    ///The cursor's column, not past the edge
+    fn last_column(&self) -> u32 {
+        self.column.min(self.columns - 1)
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = Saved {
+            column: self.column,
+            row: self.row,
+            pen: self.pen,
+        };
+    }
+
+    fn restore_cursor(&mut self) {
+        self.column = self.saved.column;
+        self.row = self.saved.row;
+        self.pen = self.saved.pen;
+    }
+
+    ///Fills whole rows, first included and end not, with the background colour
+    fn erase_rows(&mut self, first: u32, end: u32) {
+        if first >= end {
+            return;
+        }
+        let (width, bg) = (self.framebuffer.width(), self.pen.bg);
+        let y = (first * font::HEIGHT) as i32;
+        let height = (end - first) * font::HEIGHT;
+        self.framebuffer.canvas().fill_rect(0, y, width, height, bg);
+    }
+
+    ///Fills part of the cursor's row, first included and end not, with the background colour
+    fn erase_columns(&mut self, first: u32, end: u32) {
+        if first >= end {
+            return;
+        }
+        let bg = self.pen.bg;
+        let x = (first * font::WIDTH) as i32;
+        let y = (self.row * font::HEIGHT) as i32;
+        let width = (end - first) * font::WIDTH;
+        self.framebuffer.canvas().fill_rect(x, y, width, font::HEIGHT, bg);
+    }
+
+    ///ESC [ J, 0 from the cursor to the end, 1 from the start to the cursor and 2 everything
+    fn erase_display(&mut self, mode: u32) {
+        let (row, rows) = (self.row, self.rows);
+        match mode {
+            0 => {
+                self.erase_line(0);
+                self.erase_rows(row + 1, rows);
+            }
+            1 => {
+                self.erase_rows(0, row);
+                self.erase_line(1);
+            }
+            2 => self.erase_rows(0, rows),
+            _ => {}
+        }
+    }
+
+    ///ESC [ K, the same as erase_display for just the cursor's row
+    fn erase_line(&mut self, mode: u32) {
+        let (column, columns) = (self.last_column(), self.columns);
+        match mode {
+            0 => self.erase_columns(column, columns),
+            1 => self.erase_columns(0, column + 1),
+            2 => self.erase_columns(0, columns),
+            _ => {}
+        }
+    }
+
+    ///Moves to the second page if there is one, otherwise the first is cleared and lost. If the
+    ///display won't switch pages the console stays on the main screen, clearing it would lose
+    ///what was there for good
+    fn enter_alternate_screen(&mut self) {
+        if self.alternate {
+            return;
+        }
+        if self.framebuffer.page_count() > 1 && self.framebuffer.show_page(1).is_err() {
+            return;
+        }
+        self.save_cursor();
+        self.alternate = true;
+        self.clear();
+    }
+
+    ///Goes back to the first page. If the display won't switch back the console stays on the
+    ///alternate screen, so it keeps drawing on the page that is being shown
+    fn leave_alternate_screen(&mut self) {
+        if !self.alternate {
+            return;
+        }
+        if self.framebuffer.page_count() > 1 {
+            if self.framebuffer.show_page(0).is_err() {
+                return;
+            }
+        } else {
+            self.clear();
+        }
+        self.alternate = false;
+        self.restore_cursor();
+    }
+
+    ///Draws a character at the cursor and moves it on. A character written in the last column
+    ///leaves the cursor past the edge until the next one, so a full line followed by a newline
+    ///doesn't leave a blank line, the same as a real terminal
+    fn draw_char(&mut self, c: u8) {
+        if self.column >= self.columns {
+            self.column = 0;
+            self.line_feed();
+        }
+        let x = (self.column * font::WIDTH) as i32;
+        let y = (self.row * font::HEIGHT) as i32;
+        let pen = self.pen;
+        let (fg, bg) = match pen.reverse {
+            false => (pen.fg, pen.bg),
+            true => (pen.bg, pen.fg),
+        };
+        let mut canvas = self.framebuffer.canvas();
+        //Bold is drawn again one pixel to the right, kept inside the character's own cell
+        canvas.set_clip(x, y, font::WIDTH, font::HEIGHT);
+        canvas.glyph(x, y, c, fg, Some(bg));
+        if pen.bold {
+            canvas.glyph(x + 1, y, c, fg, None);
+        }
+        if pen.underline {
+            canvas.fill_rect(x, y + font::HEIGHT as i32 - 1, font::WIDTH, 1, fg);
+        }
+        self.column += 1;
+    }
+
+    ///Moves down a row, scrolling the scroll region up when at the bottom of it
+    fn line_feed(&mut self) {
+        if self.row == self.scroll_bottom {
+            let top = self.scroll_top * font::HEIGHT;
+            let bottom = (self.scroll_bottom + 1) * font::HEIGHT;
+            let bg = self.pen.bg;
+            self.framebuffer.canvas().scroll_up(top, bottom, font::HEIGHT, bg);
+        } else if self.row + 1 < self.rows {
+            self.row += 1;
+        }
+    }
+}
+
+impl fmt::Write for FramebufferConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            self.write_byte(b);
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,12 @@
+use arch::{delay_cycles, without_interrupts};
 use common::GPIO_BASE;
+use interrupt::{self, Interrupt};
+use core::fmt;
 use core::marker::PhantomData;
+use core::mem;
 use volatile::{ReadOnly, ReadWrite, WriteOnly};
 
+#[derive(Clone, Copy)]
 enum FunctionSelectMask {
     Input = 0b000,
     Output = 0b001,
@@ -60,6 +65,119 @@ struct Registers {
     PUDCLK: [ReadWrite<u32>; 2], //pull-up/down enable clock
 }
 
+///Internal pull resistor settings, the values are what gets written to PUD
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pull {
+    None = 0b00,
+    Down = 0b01,
+    Up = 0b10,
+}
+
+///Conditions the event detect logic can watch an input pin for, see page 96 of the Broadcom manual.
+///
+///The edge detectors sample the pin on the system clock so ignore very short pulses, the async
+///ones don't. Level events stay set for as long as the pin is held at that level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    RisingEdge,
+    FallingEdge,
+    HighLevel,
+    LowLevel,
+    AsyncRisingEdge,
+    AsyncFallingEdge,
+}
+
+///An event callback, run from IRQ context with the number of the pin that had the event
+pub type EventHandler = fn(u8);
+
+///Every event type, for switching them all off
+const EVENTS: [Event; 6] = [
+    Event::RisingEdge,
+    Event::FallingEdge,
+    Event::HighLevel,
+    Event::LowLevel,
+    Event::AsyncRisingEdge,
+    Event::AsyncFallingEdge,
+];
+
+///Registered event callbacks indexed by pin number
+static mut EVENT_HANDLERS: [Option<EventHandler>; 54] = [None; 54];
+
+///Events the interrupt handler had to acknowledge on pins with no callback, so polling with
+///event_detected still sees them
+static mut LATCHED_EVENTS: u64 = 0;
+
+///Who owns each pin, so two drivers can't both think they are in charge of it. A pin is owned
+///from when it is claimed until the Gpio or GpioPort holding it is dropped
+static mut OWNERS: [Option<&'static str>; 54] = [None; 54];
+
+///Reasons a pin can't be claimed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PinError {
+    ///There are only 54 pins, numbered 0 to 53
+    NoSuchPin(u8),
+    ///Something else already holds the pin
+    AlreadyOwned { pin: u8, owner: &'static str },
+    ///A GpioPort was given more pins than fit in the u32 it is written and read with
+    TooManyPins(usize),
+    ///A GpioPort was given the same pin twice
+    DuplicatePin(u8),
+}
+
+impl fmt::Display for PinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PinError::NoSuchPin(pin) => write!(f, "there is no GPIO{}", pin),
+            PinError::AlreadyOwned { pin, owner } => {
+                write!(f, "GPIO{} is already owned by {}", pin, owner)
+            }
+            PinError::TooManyPins(count) => {
+                write!(f, "{} pins won't fit in a GpioPort, it holds at most 32", count)
+            }
+            PinError::DuplicatePin(pin) => write!(f, "GPIO{} is in the GpioPort twice", pin),
+        }
+    }
+}
+
+///Marks every pin in the mask as owned, or none of them if any are taken already.
+///Interrupts are held off so a handler claiming pins can't sneak in between the check and the mark
+fn claim_pins(mask: u64, owner: &'static str) -> Result<(), PinError> {
+    without_interrupts(|| unsafe {
+        for pin in 0..54 {
+            if mask & (1 << pin) != 0 {
+                if let Some(owner) = OWNERS[pin as usize] {
+                    return Err(PinError::AlreadyOwned { pin, owner });
+                }
+            }
+        }
+        for pin in 0..54 {
+            if mask & (1 << pin) != 0 {
+                OWNERS[pin as usize] = Some(owner);
+            }
+        }
+        Ok(())
+    })
+}
+
+///Hands every pin in the mask back so it can be claimed again
+fn release_pins(mask: u64) {
+    without_interrupts(|| unsafe {
+        for pin in 0..54 {
+            if mask & (1 << pin) != 0 {
+                OWNERS[pin as usize] = None;
+            }
+        }
+    })
+}
+
+///The name given by whatever claimed the pin, None if it is free
+pub fn owner(pin: u8) -> Option<&'static str> {
+    match pin {
+        0...53 => unsafe { OWNERS[pin as usize] },
+        _ => None,
+    }
+}
+
 pub enum Uninitialised {}
 pub enum Input {}
 pub enum Output {}
@@ -71,28 +189,25 @@ pub struct Gpio<State> {
     _phantom: PhantomData<State>,
 }
 impl<T> Gpio<T> {
+    ///Moves the pin into another state. The old value is forgotten rather than dropped, the pin
+    ///stays owned by the new one
     fn transition<S>(self) -> Gpio<S> {
-        Gpio {
+        let out = Gpio {
             pin: self.pin,
-            registers: self.registers,
+            registers: unsafe { &mut *(self.registers as *mut Registers) },
             _phantom: PhantomData,
-        }
+        };
+        mem::forget(self);
+        out
     }
-}
-
-impl Gpio<Uninitialised> {
-    pub fn new(pin: u8) -> Gpio<Uninitialised> {
-        if pin > 53 {
-            panic!()
-        }
 
-        Gpio {
-            registers: unsafe { &mut *(GPIO_BASE as *mut Registers) },
-            pin: pin,
-            _phantom: PhantomData,
-        }
+    ///The BCM pin number this device controls
+    pub fn pin(&self) -> u8 {
+        self.pin
     }
 
+    ///Configures the pin as an output. This works from any state, so an already configured pin
+    ///can be switched straight over without going back through Uninitialised
     pub fn as_output(mut self) -> Gpio<Output> {
         self.update_pin_fsel(FunctionSelectMask::Output);
         self.transition()
@@ -101,18 +216,97 @@ impl Gpio<Uninitialised> {
         self.update_pin_fsel(FunctionSelectMask::Input);
         self.transition()
     }
-    pub fn as_alt<T: Into<FunctionSelectMask>>(mut self, f: T) -> Gpio<Alt> {
+    pub fn as_alt<F: Into<FunctionSelectMask>>(mut self, f: F) -> Gpio<Alt> {
         self.update_pin_fsel(f.into());
         self.transition()
     }
 
+    ///Puts the pin back the way it comes out of reset (an input) and hands back an
+    ///Uninitialised device, so it can be configured again later
+    pub fn reset(mut self) -> Gpio<Uninitialised> {
+        self.update_pin_fsel(FunctionSelectMask::Input);
+        self.transition()
+    }
+
+    ///Each FSEL register holds the 3 bit function of 10 pins, see page 92 of the Broadcom manual.
+    ///The other 9 pins in the register must be left alone
     fn update_pin_fsel(&mut self, f: FunctionSelectMask) {
-        let fsel_mumber = self.pin / 10;
-        let pin_offset = (self.pin % 10) * 3;
-        {
-            let fsel = &mut self.registers.FSEL[fsel_mumber as usize];
-            let old = fsel.read();
-            fsel.write(((f as u32) << (pin_offset)) | old);
+        let pin = self.pin;
+        let fsel = &mut self.registers.FSEL[(pin / 10) as usize];
+        let old = fsel.read();
+        fsel.write(with_function(old, pin, f));
+    }
+}
+
+///Replaces the 3 function select bits for this pin in the value of its FSEL register,
+///clearing whatever function was there before
+fn with_function(fsel: u32, pin: u8, f: FunctionSelectMask) -> u32 {
+    let pin_offset = (pin % 10) * 3;
+    (fsel & !(0b111 << pin_offset)) | ((f as u32) << pin_offset)
+}
+
+///Picks this pin's level out of the value of its LEV register. The masked bit is only ever 1 for
+///pins 0 and 32, so it is compared against 0 instead
+fn level(lev: u32, pin: u8) -> bool {
+    lev & (1 << (pin % 32)) != 0
+}
+
+///Dropping a Gpio hands the pin back to the registry. Event detection and the event callback are
+///switched off so the next owner doesn't get them, but the pin keeps whatever function it had,
+///use reset first to put it back to an input
+impl<T> Drop for Gpio<T> {
+    fn drop(&mut self) {
+        let (bank, bit) = ((self.pin / 32) as usize, 1 << (self.pin % 32));
+        let pin = self.pin;
+        let registers = &mut *self.registers;
+        without_interrupts(|| {
+            for &event in EVENTS.iter() {
+                let register = &mut event_register(registers, event)[bank];
+                let old = register.read();
+                register.write(old & !bit);
+            }
+            registers.EDS[bank].write(bit);
+            unsafe {
+                LATCHED_EVENTS &= !(1 << pin);
+            }
+            remove_handler(pin);
+        });
+        release_pins(1 << pin);
+    }
+}
+
+impl Gpio<Uninitialised> {
+    ///Claims the pin for the owner, which is just a name for the shell to show. Each pin can only
+    ///be held by one Gpio at a time, claiming it again fails until that one is dropped
+    pub fn claim(pin: u8, owner: &'static str) -> Result<Gpio<Uninitialised>, PinError> {
+        if pin > 53 {
+            return Err(PinError::NoSuchPin(pin));
+        }
+        claim_pins(1 << pin, owner)?;
+
+        Ok(Gpio::with_registers(pin, unsafe { &mut *(GPIO_BASE as *mut Registers) }))
+    }
+
+    ///Takes the pin for the owner even if something else holds it. Only for the panic handler,
+    ///which has to get the console going whatever state the kernel was left in. Whatever held the
+    ///pin before still thinks it does and will hand it back to the registry if it is dropped
+    pub fn steal(pin: u8, owner: &'static str) -> Gpio<Uninitialised> {
+        if pin > 53 {
+            panic!("there is no GPIO{}", pin)
+        }
+        without_interrupts(|| unsafe {
+            OWNERS[pin as usize] = Some(owner);
+        });
+        Gpio::with_registers(pin, unsafe { &mut *(GPIO_BASE as *mut Registers) })
+    }
+
+    ///Builds the device on top of a register block without going through the registry, so the
+    ///tests can hand it a fake block in normal memory instead of the real one
+    fn with_registers(pin: u8, registers: &'static mut Registers) -> Gpio<Uninitialised> {
+        Gpio {
+            registers: registers,
+            pin: pin,
+            _phantom: PhantomData,
         }
     }
 }
@@ -130,13 +324,408 @@ impl Gpio<Output> {
 }
 
 impl Gpio<Input> {
+    ///Sets the internal pull resistor for this pin via builder pattern, so an unconnected button
+    ///reads a steady level instead of floating
+    pub fn with_pull(mut self, pull: Pull) -> Self {
+        self.set_pull(pull);
+        self
+    }
+
+    ///Sets the internal pull resistor for this pin
+    pub fn set_pull(&mut self, pull: Pull) {
+        apply_pull(self.registers, 1 << self.pin, pull);
+    }
+
     pub fn read_level(&mut self) -> bool {
-        self.registers.LEV[(self.pin / 32) as usize].read() & (0b1 << self.pin % 32) == 1
+        level(self.registers.LEV[(self.pin / 32) as usize].read(), self.pin)
+    }
+
+    ///Gets the detect enable register bank for this event type
+    fn event_register(&mut self, event: Event) -> &mut [ReadWrite<u32>; 2] {
+        event_register(self.registers, event)
+    }
+
+    ///Starts watching this pin for an event, as well as any it was already watching for
+    pub fn enable_event(&mut self, event: Event) {
+        let (bank, bit) = ((self.pin / 32) as usize, 1 << (self.pin % 32));
+        let register = &mut self.event_register(event)[bank];
+        let old = register.read();
+        register.write(old | bit);
+    }
+
+    ///Stops watching this pin for an event
+    pub fn disable_event(&mut self, event: Event) {
+        let (bank, bit) = ((self.pin / 32) as usize, 1 << (self.pin % 32));
+        let register = &mut self.event_register(event)[bank];
+        let old = register.read();
+        register.write(old & !bit);
+    }
+
+    ///Starts watching this pin for an event via builder pattern
+    pub fn with_event(mut self, event: Event) -> Self {
+        self.enable_event(event);
+        self
+    }
+
+    ///Checks if any of the enabled events have happened since the last clear_event
+    pub fn event_detected(&self) -> bool {
+        let (bank, bit) = ((self.pin / 32) as usize, 1 << (self.pin % 32));
+        let latched = unsafe { LATCHED_EVENTS } & (1 << self.pin) != 0;
+        latched || self.registers.EDS[bank].read() & bit != 0
+    }
+
+    ///Acknowledges the event. EDS bits are cleared by writing a 1 to them
+    pub fn clear_event(&mut self) {
+        let (bank, bit) = ((self.pin / 32) as usize, 1 << (self.pin % 32));
+        let pin = self.pin;
+        without_interrupts(|| unsafe {
+            LATCHED_EVENTS &= !(1 << pin);
+        });
+        self.registers.EDS[bank].write(bit);
+    }
+
+    ///Runs the handler from IRQ context whenever one of the enabled events happens on this pin.
+    ///The event is acknowledged before the handler runs. A level would keep firing for as long as
+    ///it is held, so the interrupt switches HighLevel and LowLevel detection off for the pin
+    ///before the handler runs. The handler only gets the pin number, so whatever holds this Gpio
+    ///has to call enable_event again to watch for the level once more
+    pub fn on_event(&mut self, handler: EventHandler) {
+        let pin = self.pin;
+        without_interrupts(|| {
+            unsafe {
+                EVENT_HANDLERS[pin as usize] = Some(handler);
+            }
+            //gpio_int[3] is raised for events on any pin in either bank
+            interrupt::register(Interrupt::Gpio3, gpio_irq);
+        })
+    }
+
+    ///Stops calling the event handler for this pin, events can still be polled
+    pub fn remove_event_handler(&mut self) {
+        remove_handler(self.pin);
     }
 }
 
-/*
+///Gets the detect enable register bank for an event type
+fn event_register(registers: &mut Registers, event: Event) -> &mut [ReadWrite<u32>; 2] {
+    match event {
+        Event::RisingEdge => &mut registers.REN,
+        Event::FallingEdge => &mut registers.FEN,
+        Event::HighLevel => &mut registers.HEN,
+        Event::LowLevel => &mut registers.LEN,
+        Event::AsyncRisingEdge => &mut registers.AREN,
+        Event::AsyncFallingEdge => &mut registers.AFEN,
+    }
+}
+
+///Forgets the pin's event callback, and stops taking the GPIO interrupt once no pin has one.
+///Events can still be polled with event_detected after that
+fn remove_handler(pin: u8) {
+    without_interrupts(|| unsafe {
+        EVENT_HANDLERS[pin as usize] = None;
+        if EVENT_HANDLERS.iter().all(|handler| handler.is_none()) {
+            interrupt::unregister(Interrupt::Gpio3);
+        }
+    })
+}
+
+///Interrupt handler for the GPIO banks. Every detected event has to be acknowledged or the
+///interrupt would fire again straight away, so events on pins without a callback are latched in
+///software for event_detected to find. A level that is still held would be detected again as
+///soon as it was acknowledged, so level detection is switched off on every pin that had an event
+fn gpio_irq() {
+    let registers = unsafe { &mut *(GPIO_BASE as *mut Registers) };
+    for bank in 0..2 {
+        let status = registers.EDS[bank].read();
+        let high = registers.HEN[bank].read();
+        registers.HEN[bank].write(high & !status);
+        let low = registers.LEN[bank].read();
+        registers.LEN[bank].write(low & !status);
+        registers.EDS[bank].write(status);
+        for bit in 0..32 {
+            if status & (1 << bit) == 0 {
+                continue;
+            }
+            let pin = bank * 32 + bit;
+            match unsafe { EVENT_HANDLERS.get(pin).cloned() } {
+                Some(Some(handler)) => handler(pin as u8),
+                _ => unsafe { LATCHED_EVENTS |= 1 << pin },
+            }
+        }
+    }
+}
+
+///Sets the same pull resistor on a group of input pins in one clocked operation
+pub fn set_pulls(pins: &mut [Gpio<Input>], pull: Pull) {
+    let mask = pins.iter().fold(0u64, |mask, gpio| mask | (1 << gpio.pin));
+    if let Some(first) = pins.first_mut() {
+        apply_pull(first.registers, mask, pull);
+    }
+}
 
+///Runs the pull-up/down sequence from page 101 of the Broadcom manual for every pin in the 54 bit
+///mask. The pull setting is only latched into the pins whose PUDCLK bit is clocked, and the
+///control signal needs 150 cycles to settle on either side of that
+fn apply_pull(registers: &mut Registers, mask: u64, pull: Pull) {
+    registers.PUD.write(pull as u32);
+    delay_cycles(150);
+    registers.PUDCLK[0].write(mask as u32);
+    registers.PUDCLK[1].write((mask >> 32) as u32);
+    delay_cycles(150);
+    registers.PUD.write(Pull::None as u32);
+    registers.PUDCLK[0].write(0);
+    registers.PUDCLK[1].write(0);
+}
 
+///A group of up to 32 pins driven and read together, for parallel buses and LED bars. The pins
+///can be any of the 54, from either bank, the limit is only because write and read take a u32.
+///
+///Each bank of 32 pins is written with one SET and one CLR write, so every pin in a bank changes
+///within a couple of bus cycles of each other instead of one after the other. Pins are given in
+///bus order, bit 0 of a value written with write goes to the first pin and so on.
+pub struct GpioPort {
+    pins: [u8; 32],
+    count: usize,
+    ///54 bit mask of the pins in the port, bit n is pin n
+    mask: u64,
+    registers: &'static mut Registers,
+}
 
-*/
+impl GpioPort {
+    ///Claims every pin in the list for the owner and sets them up as outputs. Either all the pins
+    ///are claimed or, if any are already owned, none of them are
+    pub fn claim(pins: &[u8], owner: &'static str) -> Result<GpioPort, PinError> {
+        if pins.len() > 32 {
+            return Err(PinError::TooManyPins(pins.len()));
+        }
+        let mut mask = 0u64;
+        for &pin in pins {
+            if pin > 53 {
+                return Err(PinError::NoSuchPin(pin));
+            }
+            if mask & (1 << pin) != 0 {
+                return Err(PinError::DuplicatePin(pin));
+            }
+            mask |= 1 << pin;
+        }
+        claim_pins(mask, owner)?;
+
+        let registers = unsafe { &mut *(GPIO_BASE as *mut Registers) };
+        Ok(GpioPort::with_registers(pins, registers))
+    }
+
+    ///Builds the port on top of a register block without going through the registry, so the
+    ///tests can hand it a fake block. The pins have already been checked by claim
+    fn with_registers(pins: &[u8], registers: &'static mut Registers) -> GpioPort {
+        let mut order = [0; 32];
+        order[..pins.len()].copy_from_slice(pins);
+        let mut port = GpioPort {
+            pins: order,
+            count: pins.len(),
+            mask: pins.iter().fold(0, |mask, &pin| mask | (1 << pin)),
+            registers,
+        };
+        port.as_outputs();
+        port
+    }
+
+    ///54 bit mask of the pins in the port, bit n is pin n
+    pub fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    ///Number of pins in the port
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    ///Switches every pin to an output
+    pub fn as_outputs(&mut self) {
+        self.update_fsel(FunctionSelectMask::Output);
+    }
+
+    ///Switches every pin to an input, for reading back from a bidirectional bus
+    pub fn as_inputs(&mut self) {
+        self.update_fsel(FunctionSelectMask::Input);
+    }
+
+    ///Each FSEL register is only read and written once however many of its pins are in the port
+    fn update_fsel(&mut self, f: FunctionSelectMask) {
+        for fsel_number in 0..6 {
+            let pins = self.pins[..self.count].iter().filter(|&&pin| pin / 10 == fsel_number);
+            let mut fsel = self.registers.FSEL[fsel_number as usize].read();
+            let mut changed = false;
+            for &pin in pins {
+                fsel = with_function(fsel, pin, f);
+                changed = true;
+            }
+            if changed {
+                self.registers.FSEL[fsel_number as usize].write(fsel);
+            }
+        }
+    }
+
+    ///Drives the pins in the port to the matching bits of a 54 bit value, bit n is pin n.
+    ///Pins not in the port are left alone
+    pub fn write_pins(&mut self, value: u64) {
+        let set = value & self.mask;
+        let clear = !value & self.mask;
+        for bank in 0..2 {
+            let set = (set >> (32 * bank)) as u32;
+            let clear = (clear >> (32 * bank)) as u32;
+            if set != 0 {
+                self.registers.SET[bank].write(set);
+            }
+            if clear != 0 {
+                self.registers.CLR[bank].write(clear);
+            }
+        }
+    }
+
+    ///Reads the level of every pin in the port, as a 54 bit value where bit n is pin n
+    pub fn read_pins(&self) -> u64 {
+        let lower = self.registers.LEV[0].read() as u64;
+        let higher = self.registers.LEV[1].read() as u64;
+        ((higher << 32) | lower) & self.mask
+    }
+
+    ///Writes a value to the port in bus order, bit 0 goes to the first pin given to new
+    pub fn write(&mut self, value: u32) {
+        let mut pins = 0u64;
+        for (i, &pin) in self.pins[..self.count].iter().enumerate() {
+            if value & (1 << i) != 0 {
+                pins |= 1 << pin;
+            }
+        }
+        self.write_pins(pins);
+    }
+
+    ///Reads the port in bus order, bit 0 comes from the first pin given to new
+    pub fn read(&self) -> u32 {
+        let levels = self.read_pins();
+        let mut value = 0;
+        for (i, &pin) in self.pins[..self.count].iter().enumerate() {
+            if levels & (1 << pin) != 0 {
+                value |= 1 << i;
+            }
+        }
+        value
+    }
+}
+
+///Hands every pin in the port back to the registry
+impl Drop for GpioPort {
+    fn drop(&mut self) {
+        release_pins(self.mask);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///A register block in normal memory, every register starting at 0. It is handed to the Gpio
+    ///and looked at through the Gpio's own field afterwards, never through a second &mut
+    fn fake_registers() -> &'static mut Registers {
+        Box::leak(Box::new(unsafe { mem::zeroed() }))
+    }
+
+    ///Sets what the fake LEV registers read back, they are read only to the driver
+    fn set_levels(registers: &mut Registers, bank: usize, value: u32) {
+        unsafe { *(&mut registers.LEV[bank] as *mut ReadOnly<u32> as *mut u32) = value }
+    }
+
+    ///What was last written to one of the fake SET or CLR registers, they are write only to the
+    ///driver
+    fn written(register: &WriteOnly<u32>) -> u32 {
+        unsafe { *(register as *const WriteOnly<u32> as *const u32) }
+    }
+
+    ///The pins in the tests were never claimed, so they are forgotten rather than dropped to keep
+    ///them out of the registry
+    fn finish<T>(gpio: Gpio<T>) {
+        mem::forget(gpio);
+    }
+
+    #[test]
+    fn with_function_only_touches_its_own_bits() {
+        let fsel = 0b111_111_111_111_111_111_111_111_111_111;
+        assert_eq!(with_function(fsel, 13, FunctionSelectMask::Input), fsel & !(0b111 << 9));
+        assert_eq!(with_function(0, 29, FunctionSelectMask::AF5), 0b010 << 27);
+        assert_eq!(with_function(0b001 << 9, 4, FunctionSelectMask::AF0), 0b001 << 9 | 0b100 << 12);
+    }
+
+    #[test]
+    fn reconfiguring_a_pin_replaces_its_function() {
+        let registers = fake_registers();
+        //Pins 10, 11 and 13 to 19 are already set up as something
+        let neighbours = 0b011_011_011_011_011_011_000_000_011_011;
+        registers.FSEL[1].write(neighbours);
+
+        let gpio = Gpio::with_registers(14, registers).as_output();
+        assert_eq!(gpio.registers.FSEL[1].read() >> 12 & 0b111, 0b001);
+        let gpio = gpio.as_alt(AltFunction(5));
+        let fsel = gpio.registers.FSEL[1].read();
+        assert_eq!(fsel >> 12 & 0b111, 0b010);
+        assert_eq!(fsel & !(0b111 << 12), neighbours & !(0b111 << 12));
+        finish(gpio);
+    }
+
+    #[test]
+    fn reset_puts_the_pin_back_to_an_input() {
+        let registers = fake_registers();
+        registers.FSEL[4].write(0b111 << 27 | 0b101);
+        let gpio = Gpio::with_registers(49, registers).as_output().reset();
+        assert_eq!(gpio.registers.FSEL[4].read(), 0b101);
+        finish(gpio);
+    }
+
+    #[test]
+    fn read_level_works_on_every_pin() {
+        for &pin in &[0, 5, 26, 31, 32, 40, 53] {
+            let bank = (pin / 32) as usize;
+            let mut gpio = Gpio::with_registers(pin, fake_registers()).as_input();
+            set_levels(gpio.registers, bank, 1 << (pin % 32));
+            assert!(gpio.read_level(), "GPIO{} should read high", pin);
+            set_levels(gpio.registers, bank, !(1 << (pin % 32)));
+            assert!(!gpio.read_level(), "GPIO{} should read low", pin);
+            finish(gpio);
+        }
+    }
+
+    #[test]
+    fn port_spans_both_banks() {
+        let mut port = GpioPort::with_registers(&[5, 40, 53], fake_registers());
+        assert_eq!(port.mask(), 1 << 5 | 1 << 40 | 1 << 53);
+        assert_eq!(port.registers.FSEL[0].read(), 0b001 << 15);
+        assert_eq!(port.registers.FSEL[4].read(), 0b001);
+        assert_eq!(port.registers.FSEL[5].read(), 0b001 << 9);
+
+        port.write(0b101);
+        assert_eq!(written(&port.registers.SET[0]), 1 << 5);
+        assert_eq!(written(&port.registers.SET[1]), 1 << (53 - 32));
+        assert_eq!(written(&port.registers.CLR[1]), 1 << (40 - 32));
+
+        set_levels(port.registers, 0, !0);
+        set_levels(port.registers, 1, 1 << (40 - 32));
+        assert_eq!(port.read(), 0b011);
+        mem::forget(port);
+    }
+
+    #[test]
+    fn port_refuses_bad_pin_lists() {
+        let pins = [0; 33];
+        match GpioPort::claim(&pins, "test") {
+            Err(e) => assert_eq!(e, PinError::TooManyPins(33)),
+            Ok(_) => panic!("a 33 pin port was claimed"),
+        }
+        match GpioPort::claim(&[60], "test") {
+            Err(e) => assert_eq!(e, PinError::NoSuchPin(60)),
+            Ok(_) => panic!("a port with GPIO60 was claimed"),
+        }
+        match GpioPort::claim(&[4, 7, 4], "test") {
+            Err(e) => assert_eq!(e, PinError::DuplicatePin(4)),
+            Ok(_) => panic!("a port with GPIO4 in it twice was claimed"),
+        }
+    }
+}
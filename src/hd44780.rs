@@ -0,0 +1,236 @@
+/// Character LCDs driven by the Hitachi HD44780 controller, the common 16x2 and 20x4 modules.
+///
+/// The display is wired in 4 bit mode: the 4 data lines D4-D7 go to a GpioPort so each nibble
+/// lands on the bus in one go, RS and E go to their own pins and R/W is tied to ground so the
+/// display is only ever written to. Without R/W the busy flag can't be read so every command
+/// waits for the longest time the datasheet says it can take instead.
+///
+/// The HD44780 runs at 5V but is happy with 3.3V logic levels on its inputs, as long as nothing
+/// ever drives its outputs back into the Pi which is why R/W has to be grounded.
+
+use core::fmt;
+use gpio::{Gpio, GpioPort, Output};
+use timer::{spin_sleep_micros, spin_sleep_millis};
+
+///Commands, see page 24 of the Hitachi HD44780 datasheet
+const CLEAR_DISPLAY: u8 = 0x01;
+const RETURN_HOME: u8 = 0x02;
+const ENTRY_MODE_SET: u8 = 0x04;
+const DISPLAY_CONTROL: u8 = 0x08;
+const FUNCTION_SET: u8 = 0x20;
+const SET_DDRAM_ADDRESS: u8 = 0x80;
+
+///Flags for the commands above
+const ENTRY_INCREMENT: u8 = 0x02;
+const DISPLAY_ON: u8 = 0x04;
+const CURSOR_ON: u8 = 0x02;
+const BLINK_ON: u8 = 0x01;
+const TWO_LINES: u8 = 0x08;
+
+///Most commands take 37us to run, clear and home take up to 1.52ms
+const COMMAND_MICROS: u64 = 40;
+const CLEAR_MICROS: u64 = 1600;
+
+///Reasons Hd44780::new can't drive a display, the pins are handed back when it fails
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hd44780Error {
+    ///The controller drives 1 to 4 rows
+    BadRows(u8),
+    ///The controller drives 1 to 40 columns
+    BadColumns(u8),
+    ///4 bit mode needs exactly 4 data pins, this many were given
+    WrongDataPins(usize),
+}
+
+impl fmt::Display for Hd44780Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Hd44780Error::BadRows(rows) => {
+                write!(f, "HD44780 displays have 1 to 4 rows, not {}", rows)
+            }
+            Hd44780Error::BadColumns(columns) => {
+                write!(f, "HD44780 displays have 1 to 40 columns, not {}", columns)
+            }
+            Hd44780Error::WrongDataPins(count) => {
+                write!(f, "HD44780 in 4 bit mode needs 4 data pins, not {}", count)
+            }
+        }
+    }
+}
+
+///A character display in 4 bit mode
+pub struct Hd44780 {
+    ///D4-D7 in that order
+    data: GpioPort,
+    rs: Gpio<Output>,
+    enable: Gpio<Output>,
+    columns: u8,
+    rows: u8,
+    ///Row the cursor is on, for moving to the next one on a newline
+    row: u8,
+    ///Current DISPLAY_CONTROL flags
+    display_control: u8,
+}
+
+impl Hd44780 {
+    ///Constructor, data is a port of the 4 pins wired to D4, D5, D6 and D7 in that order.
+    ///Runs the initialisation by instruction sequence from page 46 of the datasheet, which works
+    ///whatever state the display was left in, then clears the screen
+    pub fn new(
+        data: GpioPort,
+        rs: Gpio<Output>,
+        enable: Gpio<Output>,
+        columns: u8,
+        rows: u8,
+    ) -> Result<Hd44780, Hd44780Error> {
+        if rows == 0 || rows > 4 {
+            return Err(Hd44780Error::BadRows(rows));
+        }
+        if columns == 0 || columns > 40 {
+            return Err(Hd44780Error::BadColumns(columns));
+        }
+        if data.len() != 4 {
+            return Err(Hd44780Error::WrongDataPins(data.len()));
+        }
+        let mut lcd = Hd44780 {
+            data,
+            rs,
+            enable,
+            columns,
+            rows,
+            row: 0,
+            display_control: DISPLAY_ON,
+        };
+        lcd.rs.clear();
+        lcd.enable.clear();
+
+        //Give the display time to power up
+        spin_sleep_millis(50);
+
+        //It could be in 8 bit mode or halfway through a 4 bit transfer, sending 0x3 three times
+        //gets it to 8 bit mode from either, then 0x2 switches to 4 bit mode
+        lcd.write_nibble(0x3);
+        spin_sleep_micros(4500);
+        lcd.write_nibble(0x3);
+        spin_sleep_micros(150);
+        lcd.write_nibble(0x3);
+        spin_sleep_micros(COMMAND_MICROS);
+        lcd.write_nibble(0x2);
+        spin_sleep_micros(COMMAND_MICROS);
+
+        let lines = match rows {
+            1 => 0,
+            _ => TWO_LINES,
+        };
+        lcd.command(FUNCTION_SET | lines);
+        lcd.command(DISPLAY_CONTROL | DISPLAY_ON);
+        lcd.command(ENTRY_MODE_SET | ENTRY_INCREMENT);
+        lcd.clear();
+        Ok(lcd)
+    }
+
+    ///Blanks the display and puts the cursor back at the top left
+    pub fn clear(&mut self) {
+        self.command(CLEAR_DISPLAY);
+        spin_sleep_micros(CLEAR_MICROS);
+        self.row = 0;
+    }
+
+    ///Puts the cursor back at the top left without clearing anything
+    pub fn home(&mut self) {
+        self.command(RETURN_HOME);
+        spin_sleep_micros(CLEAR_MICROS);
+        self.row = 0;
+    }
+
+    ///Moves the cursor, positions off the edge of the display are clamped to it
+    pub fn set_cursor(&mut self, column: u8, row: u8) {
+        let row = row.min(self.rows - 1);
+        let column = column.min(self.columns - 1);
+        self.row = row;
+        let address = self.row_offset(row) + column;
+        self.command(SET_DDRAM_ADDRESS | address);
+    }
+
+    ///Shows or hides the underline cursor and makes it blink
+    pub fn show_cursor(&mut self, visible: bool, blink: bool) {
+        self.display_control &= !(CURSOR_ON | BLINK_ON);
+        if visible {
+            self.display_control |= CURSOR_ON;
+        }
+        if blink {
+            self.display_control |= BLINK_ON;
+        }
+        let control = self.display_control;
+        self.command(DISPLAY_CONTROL | control);
+    }
+
+    ///Turns the display on or off, what is on it is kept while it is off
+    pub fn set_display(&mut self, on: bool) {
+        match on {
+            true => self.display_control |= DISPLAY_ON,
+            false => self.display_control &= !DISPLAY_ON,
+        }
+        let control = self.display_control;
+        self.command(DISPLAY_CONTROL | control);
+    }
+
+    ///Address of the first character on a row. Rows 2 and 3 of a 4 line display carry on from
+    ///the end of rows 0 and 1, so on a 20x4 they start at 20 and 0x40 + 20 and on a 16x4 at 16
+    ///and 0x40 + 16
+    fn row_offset(&self, row: u8) -> u8 {
+        match row {
+            0 => 0x00,
+            1 => 0x40,
+            2 => self.columns,
+            _ => 0x40 + self.columns,
+        }
+    }
+
+    ///Writes a character at the cursor. The character set is ASCII for 0x20 to 0x7D
+    pub fn write_char(&mut self, c: u8) {
+        self.rs.set();
+        self.write_byte(c);
+        spin_sleep_micros(COMMAND_MICROS);
+    }
+
+    fn command(&mut self, command: u8) {
+        self.rs.clear();
+        self.write_byte(command);
+        spin_sleep_micros(COMMAND_MICROS);
+    }
+
+    ///Sends a byte as two nibbles, high one first
+    fn write_byte(&mut self, b: u8) {
+        self.write_nibble(b >> 4);
+        self.write_nibble(b & 0xF);
+    }
+
+    ///Puts a nibble on D4-D7 and pulses E, the display latches the data on the falling edge.
+    ///E has to be high for at least 450ns and the data held for 10ns after. The sleeps are for 2us
+    ///because a 1us sleep can end straight away if the timer ticks just after it starts
+    fn write_nibble(&mut self, nibble: u8) {
+        self.data.write(nibble as u32);
+        self.enable.set();
+        spin_sleep_micros(2);
+        self.enable.clear();
+        spin_sleep_micros(2);
+    }
+}
+
+///Lets write! print straight to the display. A newline moves to the start of the next row
+impl fmt::Write for Hd44780 {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            match b {
+                b'\n' => {
+                    let row = (self.row + 1) % self.rows;
+                    self.set_cursor(0, row);
+                }
+                b'\r' => {}
+                _ => self.write_char(b),
+            }
+        }
+        Ok(())
+    }
+}
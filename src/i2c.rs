@@ -0,0 +1,332 @@
+/// Documentation for the BSC (Broadcom Serial Controller) can be found on page 28 of the Broadcom
+/// manual. BSC is Broadcom's name for an I2C master, BSC1 is the one on the GPIO header as I2C1.
+///
+/// The controller sends a start, the address and DLEN bytes out of (or into) its 16 byte FIFO and
+/// then a stop, all by itself. It has no way to ask for a repeated start, but starting a read
+/// while a write is still going makes it send one instead of the stop, page 36.
+
+use common::BSC1_BASE;
+use core::fmt;
+use gpio::{Alt, AltFunction, Gpio, PinError};
+use mailbox::{self, ClockId};
+use timer::current_time;
+use volatile::Volatile;
+
+///Register map from page 28 of the Broadcom manual
+///
+///This structure uses [repr(C)].
+///This means that the compiler is NOT free to reorder the fields of this structure for alignment
+///reasons, its very important the layout is exactly as ive defined it.
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    ///Control
+    C: Volatile<u32>,
+    ///Status
+    S: Volatile<u32>,
+    ///Number of bytes to transfer
+    DLEN: Volatile<u32>,
+    ///Slave address
+    A: Volatile<u32>,
+    ///Data FIFO, 16 bytes deep
+    FIFO: Volatile<u32>,
+    ///Clock divider
+    DIV: Volatile<u32>,
+    ///Data delay
+    DEL: Volatile<u32>,
+    ///Clock stretch timeout
+    CLKT: Volatile<u32>,
+}
+
+///C bits
+const C_I2CEN: u32 = 1 << 15;
+const C_ST: u32 = 1 << 7;
+const C_CLEAR: u32 = 0b11 << 4;
+const C_READ: u32 = 1 << 0;
+
+///S bits, CLKT, ERR and DONE are cleared by writing 1 to them
+const S_CLKT: u32 = 1 << 9;
+const S_ERR: u32 = 1 << 8;
+const S_RXD: u32 = 1 << 5;
+const S_TXD: u32 = 1 << 4;
+const S_DONE: u32 = 1 << 1;
+const S_TA: u32 = 1 << 0;
+
+///How many bytes the FIFO holds
+const FIFO_SIZE: usize = 16;
+
+///Core clock to assume if the mailbox can't tell us, the BSC is clocked from it
+const DEFAULT_CORE_CLOCK: u32 = 250_000_000;
+
+///Extra time allowed on top of how long a transfer should take, in microseconds
+const TIMEOUT_SLACK: u64 = 10_000;
+
+///Address of a device on the bus
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Address {
+    ///Nearly every device, 0x08 to 0x77. The addresses either side are reserved for general
+    ///call, 10 bit addressing and the like and are refused with InvalidAddress
+    Seven(u8),
+    ///0x000 to 0x3FF
+    Ten(u16),
+}
+
+impl From<u8> for Address {
+    fn from(address: u8) -> Address {
+        Address::Seven(address)
+    }
+}
+
+///Reasons a transfer can fail
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum I2cError {
+    ///Nothing acknowledged the address, or the device refused a byte
+    Nack,
+    ///A device held SCL low for longer than the clock stretch timeout
+    ClockStretchTimeout,
+    ///The transfer didn't finish in the time it should have, the bus may be stuck
+    Timeout,
+    ///The address doesn't fit in 10 bits, or is a reserved 7 bit address
+    InvalidAddress,
+    ///The write half of a write_read has to fit in the 16 byte FIFO, including the second
+    ///address byte of a 10 bit address
+    WriteTooLong,
+}
+
+impl fmt::Display for I2cError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            I2cError::Nack => "no acknowledge from device",
+            I2cError::ClockStretchTimeout => "clock stretch timeout",
+            I2cError::Timeout => "transfer timed out",
+            I2cError::InvalidAddress => "invalid address",
+            I2cError::WriteTooLong => "write too long for a repeated start",
+        };
+        f.write_str(s)
+    }
+}
+
+///Wrapper for the BSC1 registers, on GPIO2 (SDA) and GPIO3 (SCL)
+pub struct I2c {
+    registers: &'static mut Registers,
+    ///Held so nothing else can take them while the device exists
+    _pins: [Gpio<Alt>; 2],
+    ///Core clock rate in Hz that the divider is worked out from
+    core_clock: u32,
+    ///SCL frequency in Hz
+    clock: u32,
+}
+
+impl I2c {
+    ///Constructor, claims GPIO2 and 3 and starts the bus at the standard 100kHz.
+    ///The Pi has 1.8k pull-ups to 3.3V on both pins so no extra resistors are needed
+    pub fn new() -> Result<I2c, PinError> {
+        let sda = Gpio::claim(2, "i2c")?;
+        let scl = Gpio::claim(3, "i2c")?;
+        let core_clock = mailbox::clock_rate(ClockId::Core).unwrap_or(DEFAULT_CORE_CLOCK);
+        let mut i2c = I2c {
+            registers: unsafe { &mut *(BSC1_BASE as *mut Registers) },
+            //Set them to use AltFunction 0, see page 102 of the Broadcom manual
+            _pins: [sda.as_alt(AltFunction(0)), scl.as_alt(AltFunction(0))],
+            core_clock,
+            clock: 0,
+        };
+        i2c.set_clock(100_000);
+        Ok(i2c)
+    }
+
+    ///Set the SCL frequency via builder pattern, 400kHz is fast mode
+    pub fn with_clock(mut self, hz: u32) -> Self {
+        self.set_clock(hz);
+        self
+    }
+
+    ///Set how many SCL cycles a device may hold the clock low for via builder pattern.
+    ///0 waits forever
+    pub fn with_clock_stretch_timeout(self, cycles: u16) -> Self {
+        self.registers.CLKT.write(cycles as u32);
+        self
+    }
+
+    ///Sets the SCL frequency, returning the frequency actually achieved. The divider has to be
+    ///even so this rounds down to the next frequency it can make rather than going too fast
+    pub fn set_clock(&mut self, hz: u32) -> u32 {
+        let hz = hz.max(1);
+        let mut divider = (self.core_clock + hz - 1) / hz;
+        divider += divider & 1;
+        let divider = divider.max(2).min(0xFFFE);
+        self.registers.DIV.write(divider);
+        self.clock = self.core_clock / divider;
+        self.clock
+    }
+
+    ///SCL frequency in Hz
+    pub fn clock(&self) -> u32 {
+        self.clock
+    }
+
+    ///Writes the bytes to the device
+    pub fn write<A: Into<Address>>(&mut self, address: A, bytes: &[u8]) -> Result<(), I2cError> {
+        self.transfer(address.into(), bytes, &mut [])
+    }
+
+    ///Reads enough bytes from the device to fill the buffer
+    pub fn read<A: Into<Address>>(&mut self, address: A, buffer: &mut [u8]) -> Result<(), I2cError> {
+        self.transfer(address.into(), &[], buffer)
+    }
+
+    ///Writes to the device then reads from it with a repeated start in between, so no other
+    ///master can get in. This is how most devices are read, by writing the register number and
+    ///reading back its value
+    pub fn write_read<A: Into<Address>>(
+        &mut self,
+        address: A,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), I2cError> {
+        self.transfer(address.into(), bytes, buffer)
+    }
+
+    ///Checks if anything acknowledges the address, by writing nothing to it
+    pub fn probe<A: Into<Address>>(&mut self, address: A) -> bool {
+        self.write(address, &[]).is_ok()
+    }
+
+    fn transfer(&mut self, address: Address, bytes: &[u8], buffer: &mut [u8]) -> Result<(), I2cError> {
+        //A 10 bit address is sent as 11110 and its top 2 bits in the address byte, then the
+        //bottom 8 bits as the first data byte. A read sends the first part again after a
+        //repeated start, which the controller does for us when a read follows a write
+        let (slave, prefix) = match address {
+            Address::Seven(a @ 0x08...0x77) => (a as u32, None),
+            Address::Ten(a) if a <= 0x3FF => (0x78 | (a >> 8) as u32, Some(a as u8)),
+            _ => return Err(I2cError::InvalidAddress),
+        };
+        let write_len = bytes.len() + prefix.map_or(0, |_| 1);
+        let repeated_start = write_len != 0 && !buffer.is_empty();
+        if repeated_start && write_len > FIFO_SIZE {
+            return Err(I2cError::WriteTooLong);
+        }
+
+        self.reset();
+        self.registers.A.write(slave);
+        let deadline = self.deadline(write_len + buffer.len());
+        let mut out = prefix.into_iter().chain(bytes.iter().cloned());
+
+        let result = if repeated_start {
+            self.repeated_start(write_len, &mut out, buffer, deadline)
+        } else if buffer.is_empty() {
+            self.write_phase(write_len, &mut out, deadline)
+        } else {
+            self.read_phase(buffer, deadline)
+        };
+        self.finish();
+        result
+    }
+
+    ///Feeds bytes into the FIFO as it empties until the controller says it is done
+    fn write_phase<I: Iterator<Item = u8>>(
+        &mut self,
+        len: usize,
+        bytes: &mut I,
+        deadline: u64,
+    ) -> Result<(), I2cError> {
+        self.registers.DLEN.write(len as u32);
+        //Fill the FIFO before starting so the first bytes go straight out
+        self.fill_fifo(bytes);
+        self.registers.C.write(C_I2CEN | C_ST);
+        loop {
+            self.fill_fifo(bytes);
+            if self.check(deadline)? {
+                return Ok(());
+            }
+        }
+    }
+
+    ///Collects bytes out of the FIFO until the buffer is full and the controller is done
+    fn read_phase(&mut self, buffer: &mut [u8], deadline: u64) -> Result<(), I2cError> {
+        self.registers.DLEN.write(buffer.len() as u32);
+        self.registers.C.write(C_I2CEN | C_ST | C_READ);
+        self.collect(buffer, deadline)
+    }
+
+    ///Puts the whole write in the FIFO, starts it and as soon as it is under way starts the
+    ///read, which turns the stop at the end of the write into a repeated start
+    fn repeated_start<I: Iterator<Item = u8>>(
+        &mut self,
+        len: usize,
+        bytes: &mut I,
+        buffer: &mut [u8],
+        deadline: u64,
+    ) -> Result<(), I2cError> {
+        self.registers.DLEN.write(len as u32);
+        self.fill_fifo(bytes);
+        self.registers.C.write(C_I2CEN | C_ST);
+        while self.registers.S.read() & (S_TA | S_DONE) == 0 {
+            self.check(deadline)?;
+        }
+        self.registers.DLEN.write(buffer.len() as u32);
+        self.registers.C.write(C_I2CEN | C_ST | C_READ);
+        self.collect(buffer, deadline)
+    }
+
+    fn fill_fifo<I: Iterator<Item = u8>>(&mut self, bytes: &mut I) {
+        while self.registers.S.read() & S_TXD != 0 {
+            match bytes.next() {
+                Some(b) => self.registers.FIFO.write(b as u32),
+                None => return,
+            }
+        }
+    }
+
+    fn collect(&mut self, buffer: &mut [u8], deadline: u64) -> Result<(), I2cError> {
+        let mut i = 0;
+        loop {
+            while i < buffer.len() && self.registers.S.read() & S_RXD != 0 {
+                buffer[i] = self.registers.FIFO.read() as u8;
+                i += 1;
+            }
+            //Only stop once DONE is set and the FIFO has been emptied, the last bytes can
+            //arrive in the FIFO at the same time as DONE
+            let done = self.check(deadline)?;
+            if done && (i == buffer.len() || self.registers.S.read() & S_RXD == 0) {
+                return Ok(());
+            }
+        }
+    }
+
+    ///Checks for errors and timeouts, returning true once the transfer is done
+    fn check(&self, deadline: u64) -> Result<bool, I2cError> {
+        let status = self.registers.S.read();
+        if status & S_ERR != 0 {
+            return Err(I2cError::Nack);
+        }
+        if status & S_CLKT != 0 {
+            return Err(I2cError::ClockStretchTimeout);
+        }
+        if current_time() > deadline {
+            return Err(I2cError::Timeout);
+        }
+        Ok(status & S_DONE != 0)
+    }
+
+    ///Works out when a transfer of so many bytes should have finished by. Each byte is 9 clocks
+    ///with its acknowledge, plus the address and the start and stop
+    fn deadline(&self, bytes: usize) -> u64 {
+        let clocks = (bytes as u64 + 2) * 9;
+        current_time() + clocks * 1_000_000 / self.clock as u64 + TIMEOUT_SLACK
+    }
+
+    ///Empties the FIFO and clears the sticky status bits left from the last transfer
+    fn reset(&mut self) {
+        self.registers.C.write(C_I2CEN | C_CLEAR);
+        self.registers.S.write(S_CLKT | S_ERR | S_DONE);
+    }
+
+    ///Leaves the controller idle after a transfer. After an error the controller still sends a
+    ///stop, give it a moment to do so before throwing away whatever is left
+    fn finish(&mut self) {
+        let deadline = current_time() + TIMEOUT_SLACK;
+        while self.registers.S.read() & S_TA != 0 && current_time() < deadline {}
+        self.reset();
+    }
+}
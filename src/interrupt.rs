@@ -0,0 +1,227 @@
+/// Documentation for the hardware for the interrupt controller can be found on page 109 of the Broadcom manual
+
+use arch::{core_id, without_interrupts};
+use common::{INTERRUPT_BASE, LOCAL_BASE};
+use volatile::{ReadOnly, Volatile};
+
+///This is the Register table found on page 112 of the Broadcom manual, section 7.5
+///
+///This structure uses [repr(C)].
+///This means that the compiler is NOT free to reorder the fields of this structure for alignment
+///reasons, its very important the layout is exactly as ive defined it.
+#[allow(non_snake_case)]
+#[repr(C)]
+struct Registers {
+    IRQ_BASIC_PENDING: ReadOnly<u32>,
+    //Pending bits for GPU IRQs 0 -> 31 and 32 -> 63
+    IRQ_PENDING: [ReadOnly<u32>; 2],
+    FIQ_CONTROL: Volatile<u32>,
+    //Writing a 1 enables the IRQ, writing 0 has no effect
+    ENABLE_IRQS: [Volatile<u32>; 2],
+    ENABLE_BASIC_IRQS: Volatile<u32>,
+    //Writing a 1 disables the IRQ, writing 0 has no effect
+    DISABLE_IRQS: [Volatile<u32>; 2],
+    DISABLE_BASIC_IRQS: Volatile<u32>,
+}
+
+///The peripheral interrupt sources we have drivers for, the values are the GPU IRQ numbers
+///in the table on page 113 of the Broadcom manual
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+    //System timer compare channels 1 and 3, channels 0 and 2 are used by the GPU
+    Timer1 = 1,
+    Timer3 = 3,
+    Usb = 9,
+    //Mini Uart and the two auxiliary SPI masters
+    Aux = 29,
+    I2cSpiSlave = 43,
+    Pwa0 = 45,
+    Pwa1 = 46,
+    Smi = 48,
+    Gpio0 = 49,
+    Gpio1 = 50,
+    Gpio2 = 51,
+    Gpio3 = 52,
+    I2c = 53,
+    Spi = 54,
+    Pcm = 55,
+    Uart = 57,
+}
+
+impl Interrupt {
+    ///Which of the two 32 bit banks this IRQ is in and the bit within that bank
+    fn bank_and_bit(self) -> (usize, u32) {
+        let irq = self as usize;
+        (irq / 32, 1 << (irq % 32))
+    }
+}
+
+///The ARM local peripherals that sit in front of each core, documented in the QA7 manual
+///("Quad-A7 control") section 4. Only the per-core interrupt routing is mapped here.
+#[allow(non_snake_case)]
+#[repr(C)]
+struct LocalRegisters {
+    //0x00 -> 0x40, control, prescaler, mailbox and local timer registers we don't use
+    _a: [u32; 16],
+    //Which per-core generic timer interrupts are routed to each core as IRQs
+    TIMER_IRQ_CONTROL: [Volatile<u32>; 4],
+    MAILBOX_IRQ_CONTROL: [Volatile<u32>; 4],
+    //What is asserting the IRQ line of each core
+    IRQ_SOURCE: [ReadOnly<u32>; 4],
+    FIQ_SOURCE: [ReadOnly<u32>; 4],
+}
+
+///Per-core interrupt sources, the values are bits in TIMER_IRQ_CONTROL and IRQ_SOURCE
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LocalInterrupt {
+    //Generic timer interrupts, the physical timer at EL1 is the non-secure one
+    SecurePhysicalTimer = 0,
+    PhysicalTimer = 1,
+    HypervisorTimer = 2,
+    VirtualTimer = 3,
+}
+
+///Bit 8 of IRQ_SOURCE means one of the GPU interrupts above is pending
+const LOCAL_SOURCE_GPU: u32 = 1 << 8;
+
+///An interrupt handler. There is no allocator so closures that capture can't be stored,
+///anything a handler needs has to live in a static
+pub type Handler = fn();
+
+///Table of registered handlers indexed by GPU IRQ number
+static mut HANDLERS: [Option<Handler>; 64] = [None; 64];
+
+///Table of registered per-core handlers for each core, indexed by LocalInterrupt
+static mut LOCAL_HANDLERS: [[Option<Handler>; 4]; 4] = [[None; 4]; 4];
+
+fn local_registers() -> &'static mut LocalRegisters {
+    unsafe { &mut *(LOCAL_BASE as *mut LocalRegisters) }
+}
+
+///Wrapper for the static pointer to the interrupt controller registers
+pub struct Controller {
+    registers: &'static mut Registers,
+}
+
+impl Controller {
+    ///Constructor, casts the INTERRUPT_BASE address defined in common.rs as a Registers struct
+    pub fn new() -> Controller {
+        Controller {
+            registers: unsafe { &mut *(INTERRUPT_BASE as *mut Registers) },
+        }
+    }
+
+    ///Lets this interrupt source through to the core
+    pub fn enable(&mut self, int: Interrupt) {
+        let (bank, bit) = int.bank_and_bit();
+        self.registers.ENABLE_IRQS[bank].write(bit);
+    }
+
+    ///Stops this interrupt source reaching the core
+    pub fn disable(&mut self, int: Interrupt) {
+        let (bank, bit) = int.bank_and_bit();
+        self.registers.DISABLE_IRQS[bank].write(bit);
+    }
+
+    ///Checks if this interrupt source is currently asserted
+    pub fn is_pending(&self, int: Interrupt) -> bool {
+        let (bank, bit) = int.bank_and_bit();
+        self.registers.IRQ_PENDING[bank].read() & bit != 0
+    }
+
+    ///Reads both pending banks as one 64 bit value, bit n is GPU IRQ n
+    fn pending(&self) -> u64 {
+        let lower = self.registers.IRQ_PENDING[0].read() as u64;
+        let higher = self.registers.IRQ_PENDING[1].read() as u64;
+        (higher << 32) | lower
+    }
+}
+
+///Registers a handler for an interrupt source and enables it, replacing any previous handler.
+///The handler runs in IRQ context with IRQs masked so it should be short
+pub fn register(int: Interrupt, handler: Handler) {
+    without_interrupts(|| {
+        unsafe {
+            HANDLERS[int as usize] = Some(handler);
+        }
+        Controller::new().enable(int);
+    })
+}
+
+///Disables an interrupt source and removes its handler
+pub fn unregister(int: Interrupt) {
+    without_interrupts(|| {
+        Controller::new().disable(int);
+        unsafe {
+            HANDLERS[int as usize] = None;
+        }
+    })
+}
+
+///Registers a handler for one of this core's own interrupt sources and routes it to this core.
+///Unlike the GPU sources these can't be acknowledged here, the handler has to deal with the
+///peripheral (e.g. re-arm or stop the generic timer) or it will fire again immediately
+pub fn register_local(int: LocalInterrupt, handler: Handler) {
+    let core = core_id();
+    without_interrupts(|| {
+        unsafe {
+            LOCAL_HANDLERS[core][int as usize] = Some(handler);
+        }
+        let control = &mut local_registers().TIMER_IRQ_CONTROL[core];
+        let old = control.read();
+        control.write(old | (1 << int as u32));
+    })
+}
+
+///Stops one of this core's own interrupt sources being routed to it and removes its handler
+pub fn unregister_local(int: LocalInterrupt) {
+    let core = core_id();
+    without_interrupts(|| {
+        let control = &mut local_registers().TIMER_IRQ_CONTROL[core];
+        let old = control.read();
+        control.write(old & !(1 << int as u32));
+        unsafe {
+            LOCAL_HANDLERS[core][int as usize] = None;
+        }
+    })
+}
+
+///Called from the IRQ vector, runs the handler for every pending source.
+///
+///A pending source with no handler would fire again as soon as we return, so it is disabled
+///instead of letting it lock up the core
+pub fn dispatch() {
+    let core = core_id();
+    let source = local_registers().IRQ_SOURCE[core].read();
+    for int in 0..4 {
+        if source & (1 << int) == 0 {
+            continue;
+        }
+        match unsafe { LOCAL_HANDLERS[core][int] } {
+            Some(handler) => handler(),
+            None => {
+                let control = &mut local_registers().TIMER_IRQ_CONTROL[core];
+                let old = control.read();
+                control.write(old & !(1 << int));
+            }
+        }
+    }
+    if source & LOCAL_SOURCE_GPU == 0 {
+        return;
+    }
+
+    let controller = Controller::new();
+    let pending = controller.pending();
+    for irq in 0..64 {
+        if pending & (1 << irq) == 0 {
+            continue;
+        }
+        match unsafe { HANDLERS[irq] } {
+            Some(handler) => handler(),
+            None => {
+                let (bank, bit) = (irq / 32, 1 << (irq % 32));
+                controller.registers.DISABLE_IRQS[bank].write(bit);
+            }
+        }
+    }
+}
@@ -5,13 +5,16 @@
 #![no_builtins]
 
 //Disables the standard library. Links against libcore instead which is far more limited.
-#![no_std]
+//Tests are built for the host with the standard library so they can use its test harness
+#![cfg_attr(not(test), no_std)]
 
 //pub mod lang_items;
 //use core::ptr::{read_volatile, write_volatile};
 
 //External libraries
 /// Tiny libc implementation. Provides memset and memcpy, used for clearing the IO buffer.
+/// The host's libc provides them for tests
+#[cfg(not(test))]
 extern crate rlibc; 
 
 /// This is a very small library that provides the volatile wrapper for structures
@@ -23,166 +26,390 @@ extern crate rlibc;
 /// however its easy to forget to use them by accident and hard to find them if they're missing so using the volatile wrappers for the types ala C can save a headache
 extern crate volatile; 
 
+/// The modules all use core paths, which have to be linked by hand when building with std for tests
+#[cfg(test)]
+extern crate core;
+
 /// My modules
+mod arch;
+mod button;
+mod canvas;
+mod clock_manager;
 mod common;
+mod dma;
+mod exception;
+mod font;
+mod framebuffer;
 mod gpio;
+mod hd44780;
+mod i2c;
+mod interrupt;
+mod mailbox;
+mod pl011;
 mod prettyprinter;
+mod pwm;
+mod ring_buffer;
+mod serial;
+mod soft_pwm;
+mod spi;
 mod stdio;
 mod timer;
+mod timer_wheel;
 mod uart;
 
 ///Imports
+use arch::{current_el, disable_irqs, enable_irqs, wfi, ExceptionLevel};
+use button::{Button, ButtonEvent};
 use core::fmt::Write;
-use core::sync::atomic::AtomicBool;
+use framebuffer::{Framebuffer, FramebufferConfig, FramebufferConsole};
 use gpio::*;
 use prettyprinter::*;
 use stdio::{stdin, Stdio};
+use soft_pwm::{Effect, LedId, Repeat, Step, FULL};
 use timer::spin_sleep_millis;
-use uart::Uart;
+use pl011::{Pl011, Pl011Config};
+use serial::{ConsoleDevice, SerialPort};
+use uart::{Uart, UartConfig};
 
 ///Error handling personality, the behaviour of theerror handling, which is Abort for this, do no stack unwind.
 /// more info <https://doc.rust-lang.org/1.4.0/book/no-stdlib.html>
+#[cfg(not(test))]
 #[lang = "eh_personality"]
 pub extern "C" fn eh_personality() {}
 
 ///Code executed on panics
 /// more info <https://doc.rust-lang.org/1.4.0/book/no-stdlib.html>
+#[cfg(not(test))]
 #[lang = "panic_fmt"]
 #[no_mangle]
 pub extern "C" fn panic_fmt(args: core::fmt::Arguments, _: &(&'static str, u32)) -> ! {
-    let mut uart = Uart::new();
+    //Whatever state the console was left in can't be trusted, start it again from scratch
+    serial::reset_console();
     loop {
-        uart.set_bg_colour(BG_RED);
-        uart.set_fg_colour(FG_YELLOW);
-        uart.write_fmt(args).unwrap();
-        spin_sleep_millis(1000);
-        uart.clr();
+        serial::with_console(|console| {
+            console.set_bg_colour(BG_RED);
+            console.set_fg_colour(FG_YELLOW);
+            console.write_fmt(args).unwrap();
+            spin_sleep_millis(1000);
+            console.clr();
+        });
     }
 }
 
-///Main function for the kernel
-#[no_mangle]
+///Which UART the shell runs on. The Mini Uart is on the GPIO header by default on the Pi 3,
+///QEMU's raspi3 machine and boards with Bluetooth disabled route the PL011 there instead, build
+///with the pl011_console feature for those
+#[cfg(not(feature = "pl011_console"))]
+const CONSOLE_DEVICE: ConsoleDevice = ConsoleDevice::MiniUart;
+#[cfg(feature = "pl011_console")]
+const CONSOLE_DEVICE: ConsoleDevice = ConsoleDevice::Pl011;
+
+///Sets up a UART and registers it as the console
+fn init_console(device: ConsoleDevice) {
+    match device {
+        ConsoleDevice::MiniUart => {
+            let mut uart = Uart::new().with_auto_flow_control().with_interrupts(); //Use builder pattern to create Uart device
+            //Work the baud rate divisor out from the real core clock, if the firmware doesn't tell us
+            //it the 250MHz default from Uart::new is the best guess we have anyway
+            let configured = uart.configure(&UartConfig::new());
+            serial::set_console(uart);
+            serial::with_console(|console| match configured {
+                Ok(report) => write!(console, "Mini Uart at {}\r\n", report),
+                Err(e) => write!(console, "Mini Uart left at 115200 baud for a 250MHz clock: {}\r\n", e),
+            }).expect("error printing string");
+        }
+        ConsoleDevice::Pl011 => {
+            let mut pl011 = Pl011::new();
+            let configured = pl011.configure(&Pl011Config::new());
+            serial::set_console(pl011);
+            serial::with_console(|console| match configured {
+                Ok(baud) => write!(console, "PL011 at {} baud\r\n", baud),
+                Err(e) => write!(console, "PL011 left at 115200 baud for a 48MHz clock: {}\r\n", e),
+            }).expect("error printing string");
+        }
+    }
+}
+
+///Resolution of the HDMI console, 80 by 60 characters
+const SCREEN_WIDTH: u32 = 640;
+const SCREEN_HEIGHT: u32 = 480;
+
+///Asks the firmware for a framebuffer and mirrors the console onto it, so the shell appears on
+///HDMI (or QEMU's display) as well. If the firmware won't give us one the shell carries on with
+///just the UART. The buffer is two screens high, the second is the alternate screen
+fn init_screen() {
+    let config = FramebufferConfig::new(SCREEN_WIDTH, SCREEN_HEIGHT)
+        .virtual_size(SCREEN_WIDTH, SCREEN_HEIGHT * 2);
+    match Framebuffer::new(&config).and_then(FramebufferConsole::new) {
+        Ok(screen) => serial::set_mirror(screen),
+        Err(e) => serial::with_console(|console| write!(console, "No framebuffer: {}\r\n", e))
+            .expect("error printing string"),
+    }
+}
+
+///Main function for the kernel. Only exported for the boot code, in tests it would pull the
+///hardware code into the host build
+#[cfg_attr(not(test), no_mangle)]
 pub unsafe extern "C" fn kmain() {
-    
+    //The boot code in crt0.S should have dropped us down to EL1, nothing below works otherwise
+    assert_eq!(current_el(), ExceptionLevel::EL1, "kernel not running in EL1");
+
     let mut stdin = stdin().unwrap(); //Get stdin handle
-    let mut uart = Uart::new().with_auto_flow_control(); //Use builder pattern to create Uart device
-    let mut led1 = Gpio::new(20).as_output(); //Create GPIO device on GPIO20 and set it as an output
-    let mut led2 = Gpio::new(21).as_output();
-    led1.set(); //Turn on the LED
-    led2.set();
+    init_console(CONSOLE_DEVICE);
+    init_screen();
+    let led1 = Gpio::claim(20, "led1").expect("GPIO20 taken").as_output(); //Claim GPIO20 and set it as an output
+    let led2 = Gpio::claim(21, "led2").expect("GPIO21 taken").as_output();
+    //Hand the LEDs to the software PWM engine so they can be dimmed and run effects
+    let led1 = soft_pwm::attach(led1).expect("no room for LED1");
+    let led2 = soft_pwm::attach(led2).expect("no room for LED2");
+    //Push button between GPIO26 and ground, it wakes us up with an edge interrupt when pressed
+    let button_pin = Gpio::claim(BUTTON_PIN, "button").expect("button pin taken");
+    let mut button = Button::new(button_pin.as_input()).with_interrupts();
+    soft_pwm::set_brightness(led1, FULL); //Turn on the LED
+    soft_pwm::set_brightness(led2, FULL);
     stdin.clear(); //zero out the stdin buffer and reset its cursor
+    enable_irqs(); //Everything is set up, let registered interrupt handlers run
     loop { //Infinite loop
-        if uart.has_byte() { // If the Uart device has received a transmission
-            let byte = uart.read_byte(); // Read the data it was sent
-            stdin
-                .push(byte)
-                .expect("Error pushing to stdin, probably full"); // Add this input from the user to the stdin buffer
-            uart.write_byte(byte); // Write the users input back to them (otherwise they cant see their own keypresses)
-            if !test_for_special_char(byte, &mut stdin, &mut uart) { //Check for ctrl-c basically
-                evaluate_stdin_buffer(&mut stdin, &mut led1, &mut led2, &mut uart); //Compare the text in stdin to the preset commands
-            }
+        while let Some(event) = button.poll() {
+            handle_button(event, led1, led2);
         }
+        serial::with_console(|console| {
+            if console.has_byte() { // If the console has received a transmission
+                let byte = console.read_byte(); // Read the data it was sent
+                stdin
+                    .push(byte)
+                    .expect("Error pushing to stdin, probably full"); // Add this input from the user to the stdin buffer
+                console.write_byte(byte); // Write the users input back to them (otherwise they cant see their own keypresses)
+                if !test_for_special_char(byte, &mut stdin, console) { //Check for ctrl-c basically
+                    evaluate_stdin_buffer(&mut stdin, led1, led2, console); //Compare the text in stdin to the preset commands
+                }
+            } else if !button.needs_polling() {
+                //Nothing to do until the console brings in another byte or the button interrupts.
+                //Checking again with IRQs masked means a byte arriving just now still wakes us up
+                //instead of being missed
+                disable_irqs();
+                if !console.has_byte() {
+                    wfi();
+                }
+                enable_irqs();
+            }
+        });
     }
 }
+///GPIO pin the front panel button is wired to
+const BUTTON_PIN: u8 = 26;
+
+///Runs the shell command the button is mapped to: holding it lights LED2 and a long press
+///starts program 1, the same as typing the commands
+fn handle_button(event: ButtonEvent, led1: LedId, led2: LedId) {
+    match event {
+        ButtonEvent::Press => soft_pwm::set_brightness(led2, FULL),
+        ButtonEvent::Release => soft_pwm::set_brightness(led2, 0),
+        ButtonEvent::LongPress => prog_1(led1, led2),
+        ButtonEvent::DoubleClick => {}
+    }
+}
+
 ///This function checks for the ctrl-c escape key, which has an ASCII value of 3.Alt
 /// If it finds it, it clears the screen and clears stdin
-fn test_for_special_char(byte: u8, stdin: &mut Stdio, uart: &mut Uart) -> bool {
+fn test_for_special_char(byte: u8, stdin: &mut Stdio, console: &mut SerialPort) -> bool {
     match byte {
         //Escape key
         3 => {
             stdin.clear();
-            uart.clr();
+            console.clr();
             true
         }
         _ => false,
     }
 }
 ///This function compares the contents of stdin with preset text commands and executes them if it finds them
-fn evaluate_stdin_buffer(
-    stdin: &mut Stdio,
-    led1: &mut Gpio<Output>,
-    led2: &mut Gpio<Output>,
-    uart: &mut Uart,
-) {
+fn evaluate_stdin_buffer(stdin: &mut Stdio, led1: LedId, led2: LedId, console: &mut SerialPort) {
     match stdin.as_str().expect("Error on line 81") {
         "led1 on" => {
-            led1.set();
+            soft_pwm::set_brightness(led1, FULL);
             stdin.clear();
-            uart.clr();
+            console.clr();
         }
         "led1 off" => {
-            led1.clear();
+            soft_pwm::set_brightness(led1, 0);
             stdin.clear();
-            uart.clr();
+            console.clr();
         }
         "led2 on" => {
-            led2.set();
+            soft_pwm::set_brightness(led2, FULL);
             stdin.clear();
-            uart.clr();
+            console.clr();
         }
         "led2 off" => {
-            led2.clear();
+            soft_pwm::set_brightness(led2, 0);
             stdin.clear();
-            uart.clr();
+            console.clr();
         }
         "prog1 on" => {
             stdin.clear();
-            prog_1(stdin, uart, led1, led2);
+            console.clr();
+            prog_1(led1, led2);
+        }
+        "prog1 off" => {
+            stdin.clear();
+            console.clr();
+            soft_pwm::set_brightness(led1, 0);
+            soft_pwm::set_brightness(led2, 0);
+        }
+        "el" => {
+            stdin.clear();
+            console.clr();
+            write!(console, "Running in {}\r\n", current_el()).expect("error printing string");
+        }
+        "board" => {
+            stdin.clear();
+            console.clr();
+            board_info(console);
+        }
+        "pins" => {
+            stdin.clear();
+            console.clr();
+            list_pins(console);
         }
         "help" => {
             stdin.clear();
-            help(uart)
+            help(console)
+        }
+        command => {
+            //"led1 <effect>" and "led2 <effect>" start one of the effects in soft_pwm::EFFECTS,
+            //"led1 stop" leaves the LED where the effect had got to. A name that isn't an
+            //effect could still be half typed, so it is only reported once enter is pressed
+            let entered = command.ends_with('\r') || command.ends_with('\n');
+            let command = command.trim_right();
+            let (led, name) = match command.split_at(command.find(' ').unwrap_or(0)) {
+                ("led1", name) => (led1, &name[1..]),
+                ("led2", name) => (led2, &name[1..]),
+                _ => return,
+            };
+            if name == "stop" {
+                soft_pwm::stop(led);
+            } else if let Some(effect) = soft_pwm::find_effect(name) {
+                soft_pwm::start(led, effect);
+            } else if entered {
+                write!(console, "\nNo effect called {}, try", name).expect("error printing string");
+                for effect in soft_pwm::EFFECTS.iter() {
+                    write!(console, " {}", effect.name).expect("error printing string");
+                }
+                console.write_str(" or stop\r\n").expect("error printing string");
+                stdin.clear();
+                return;
+            } else {
+                return;
+            }
+            stdin.clear();
+            console.clr();
         }
-        _ => {}
     }
 }
 
-///This function turns on LED's in a predetermined pattern
-fn prog_1(stdin: &mut Stdio, uart: &mut Uart, led1: &mut Gpio<Output>, led2: &mut Gpio<Output>) {
-    uart.clr();
-    stdin.clear();
-    uart.set_fg_colour(FG_GREEN); //Sets foreground text to green
-    uart.write_str("Press k to end the program").unwrap();
-    let mut i = 60;
-    while i > 0 {
-        if uart.has_byte() {
-            if uart.read_byte() == 'k' as u8 {
-                break;
-            }
+///Program 1 as effects, LED1 is on from 1 to 3 seconds of every 4 and LED2 from 2 to 4.
+///It runs for a minute
+static PROG1_LED1: Effect = Effect {
+    name: "prog1",
+    steps: &[
+        Step::Set(0),
+        Step::Hold(1000),
+        Step::Set(FULL),
+        Step::Hold(2000),
+        Step::Set(0),
+        Step::Hold(1000),
+    ],
+    repeat: Repeat::Times(15),
+};
+static PROG1_LED2: Effect = Effect {
+    name: "prog1",
+    steps: &[Step::Set(0), Step::Hold(2000), Step::Set(FULL), Step::Hold(2000)],
+    repeat: Repeat::Times(15),
+};
+
+///This function turns on LED's in a predetermined pattern. It runs in the background so the
+///shell keeps working, "prog1 off" stops it
+fn prog_1(led1: LedId, led2: LedId) {
+    soft_pwm::start(led1, &PROG1_LED1);
+    soft_pwm::start(led2, &PROG1_LED2);
+}
+
+///Asks the firmware about the board and prints what it says
+fn board_info(console: &mut SerialPort) {
+    use mailbox::{BoardRevision, BoardSerial, ClockId, ClockRate, FirmwareRevision, Memory,
+                  MemoryOwner, Temperature};
+    let mut revision = BoardRevision::new();
+    let mut serial = BoardSerial::new();
+    let mut firmware = FirmwareRevision::new();
+    let mut arm_memory = Memory::new(MemoryOwner::Arm);
+    let mut vc_memory = Memory::new(MemoryOwner::VideoCore);
+    let mut temperature = Temperature::new();
+    let mut arm_clock = ClockRate::new(ClockId::Arm);
+    let mut core_clock = ClockRate::new(ClockId::Core);
+    let result = mailbox::request(&mut [
+        &mut revision,
+        &mut serial,
+        &mut firmware,
+        &mut arm_memory,
+        &mut vc_memory,
+        &mut temperature,
+        &mut arm_clock,
+        &mut core_clock,
+    ]);
+    if let Err(e) = result {
+        write!(console, "Mailbox request failed: {}\r\n", e).expect("error printing string");
+        return;
+    }
+    write!(
+        console,
+        "Board revision: {:#x}\r\nSerial: {:016x}\r\nFirmware: {}\r\n\
+         ARM memory: {}MB at {:#x}\r\nGPU memory: {}MB at {:#x}\r\n\
+         Temperature: {}.{:03}C\r\nARM clock: {}MHz\r\nCore clock: {}MHz\r\n",
+        revision.revision,
+        serial.serial,
+        firmware.revision,
+        arm_memory.size / (1024 * 1024),
+        arm_memory.base,
+        vc_memory.size / (1024 * 1024),
+        vc_memory.base,
+        temperature.millidegrees / 1000,
+        temperature.millidegrees % 1000,
+        arm_clock.rate / 1_000_000,
+        core_clock.rate / 1_000_000
+    ).expect("error printing string");
+}
+
+///Lists which driver owns each pin that has been claimed
+fn list_pins(console: &mut SerialPort) {
+    for pin in 0..54 {
+        if let Some(owner) = gpio::owner(pin) {
+            write!(console, "GPIO{}: {}\r\n", pin, owner).expect("error printing string");
         }
-        spin_sleep_millis(1000); //Use a spinlock style sleep for 1 second, this is where interrupts would have helped
-        led1.set();
-        spin_sleep_millis(1000);
-        led2.set();
-        spin_sleep_millis(1000);
-        led1.clear();
-        spin_sleep_millis(1000);
-        led2.clear();
-        i -= 4;
     }
-    led1.clear();
-    led2.clear();
-    uart.clr();
-    stdin.clear();
-    uart.set_fg_colour(FG_CLEAR);
 }
 
 ///Display some help text
-fn help(uart: &mut Uart) {
-    uart.clr();
-    uart.set_bg_colour(BG_BLUE);
-    uart.set_fg_colour(FG_WHITE);
-    uart.write_str(
+fn help(console: &mut SerialPort) {
+    console.clr();
+    console.set_bg_colour(BG_BLUE);
+    console.set_fg_colour(FG_WHITE);
+    console.write_str(
         "\r
 \r\n
 Help\r\n
 led1 on : turns on LED1\r
 led2 off : turns off LED2\r
-prog1 on : turns on program 1\r
+prog1 on : turns on program 1, prog1 off stops it\r
+led1 blink : runs an effect on LED1, also breathe, fadein, fadeout, heartbeat and stop\r
+el : shows the exception level the kernel is running in\r
+board : shows what the firmware says about the board\r
+pins : lists the GPIO pins in use and what owns them\r
+holding the button on GPIO26 turns on LED2, a long press starts program 1\r
 help: shows this message\r
 pressing ctrl+c will clear the input buffer \r
     ",
     ).expect("error printing string");
-    uart.set_bg_colour(BG_CLEAR);
-    uart.set_fg_colour(FG_CLEAR);
+    console.set_bg_colour(BG_CLEAR);
+    console.set_fg_colour(FG_CLEAR);
 }
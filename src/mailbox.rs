@@ -0,0 +1,768 @@
+/// The VideoCore mailbox, used to ask the GPU firmware for things the ARM can't see directly.
+///
+/// There's no official Broadcom documentation for this, the reference is the firmware wiki at
+/// <https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface>
+///
+/// A request is a buffer of tags, each asking one question or changing one setting. The buffer
+/// is handed to the firmware through channel 8 and comes back with the answers written over the
+/// questions. Every tag here is a struct implementing Tag, several can go in one request:
+///
+/// let mut revision = BoardRevision::new();
+/// let mut temperature = Temperature::new();
+/// mailbox::request(&mut [&mut revision, &mut temperature])?;
+///
+/// Requests share one static buffer so they must not be made from interrupt handlers.
+
+use common::MAILBOX_BASE;
+use core::fmt;
+use core::ptr::{read_volatile, write_volatile};
+use timer::current_time;
+use volatile::{ReadOnly, Volatile, WriteOnly};
+
+///Mailbox 0 is read by the ARM, mailbox 1 is written by the ARM. They are laid out one after the
+///other so the whole block is mapped as one struct.
+///
+///This structure uses [repr(C)].
+///This means that the compiler is NOT free to reorder the fields of this structure for alignment
+///reasons, its very important the layout is exactly as ive defined it.
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    READ: ReadOnly<u32>,
+    _a: [u32; 3],
+    PEEK: ReadOnly<u32>,
+    SENDER: ReadOnly<u32>,
+    STATUS: ReadOnly<u32>,
+    CONFIG: Volatile<u32>,
+    WRITE: WriteOnly<u32>,
+}
+
+///STATUS bits, set when the mailbox can't take another message or has nothing to read
+const MAIL_FULL: u32 = 0x8000_0000;
+const MAIL_EMPTY: u32 = 0x4000_0000;
+
+///Channel 8 is the property tag interface, ARM to VideoCore
+const CHANNEL_PROPERTY: u32 = 8;
+
+///Buffer codes, the first is sent in every request and the others come back in the response
+const REQUEST: u32 = 0;
+const RESPONSE_SUCCESS: u32 = 0x8000_0000;
+const RESPONSE_ERROR: u32 = 0x8000_0001;
+
+///Set in a tag's length word by the firmware when it has answered the tag
+const TAG_RESPONSE: u32 = 0x8000_0000;
+
+///Size of the buffer in words
+const BUFFER_WORDS: usize = 64;
+
+///Largest value buffer a tag can have in words, every tag here fits
+pub const MAX_VALUE_WORDS: usize = 8;
+
+///How long the firmware gets to answer, in microseconds. Most requests take well under a
+///millisecond but setting clocks and powering things up can take a while
+const TIMEOUT: u64 = 1_000_000;
+
+///Reasons a request can fail
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MailboxError {
+    ///The tags don't fit in the buffer
+    BufferTooSmall,
+    ///The firmware didn't reply in time
+    Timeout,
+    ///The firmware couldn't make sense of the buffer
+    RequestFailed,
+    ///The firmware replied without filling in the response code
+    NoResponse,
+    ///The firmware didn't answer this tag, usually because it doesn't know it
+    TagNotAnswered(u32),
+    ///The firmware answered this tag with fewer bytes than it should have
+    ShortResponse(u32),
+}
+
+impl fmt::Display for MailboxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MailboxError::BufferTooSmall => write!(f, "request too big for the mailbox buffer"),
+            MailboxError::Timeout => write!(f, "no reply from the firmware"),
+            MailboxError::RequestFailed => write!(f, "the firmware couldn't parse the request"),
+            MailboxError::NoResponse => write!(f, "the firmware didn't fill in a response"),
+            MailboxError::TagNotAnswered(tag) => write!(f, "tag {:#010x} not answered", tag),
+            MailboxError::ShortResponse(tag) => write!(f, "tag {:#010x} response too short", tag),
+        }
+    }
+}
+
+///One question or setting in a request. Implementations hold the request values before the
+///call and the response values after it
+pub trait Tag {
+    ///Tag identifier from the firmware wiki
+    fn id(&self) -> u32;
+
+    ///Size of the value buffer in words, big enough for both the request and the response
+    fn words(&self) -> usize;
+
+    ///Fills in the request values, most tags that only get something don't have any
+    fn write_request(&self, _values: &mut [u32]) {}
+
+    ///Takes the response values out of the value buffer
+    fn read_response(&mut self, values: &[u32]);
+}
+
+///The low 4 bits of a mailbox message are the channel and the high 28 are the buffer address,
+///so the buffer has to be 16 byte aligned
+#[repr(C, align(16))]
+struct Buffer([u32; BUFFER_WORDS]);
+
+///Statically allocated storage for requests, the GPU reads and writes this directly
+static mut BUFFER: Buffer = Buffer([0; BUFFER_WORDS]);
+
+///Builds a request in the static buffer one tag at a time
+struct PropertyBuffer {
+    words: *mut u32,
+    ///Where the next tag goes
+    len: usize,
+}
+
+impl PropertyBuffer {
+    fn new() -> PropertyBuffer {
+        PropertyBuffer {
+            words: unsafe { BUFFER.0.as_mut_ptr() },
+            //Leave room for the size and the request code
+            len: 2,
+        }
+    }
+
+    fn write(&mut self, index: usize, value: u32) {
+        unsafe { write_volatile(self.words.offset(index as isize), value) }
+    }
+
+    fn read(&self, index: usize) -> u32 {
+        unsafe { read_volatile(self.words.offset(index as isize)) }
+    }
+
+    ///Adds a tag: its id, the size of its value buffer in bytes, the request code and then the
+    ///value buffer itself
+    fn push(&mut self, tag: &Tag) -> Result<(), MailboxError> {
+        let words = tag.words();
+        //3 header words plus the end tag after this one
+        if words > MAX_VALUE_WORDS || self.len + 3 + words + 1 > BUFFER_WORDS {
+            return Err(MailboxError::BufferTooSmall);
+        }
+        let mut values = [0; MAX_VALUE_WORDS];
+        tag.write_request(&mut values[..words]);
+
+        let start = self.len;
+        self.write(start, tag.id());
+        self.write(start + 1, (words * 4) as u32);
+        self.write(start + 2, REQUEST);
+        for (i, value) in values[..words].iter().enumerate() {
+            self.write(start + 3 + i, *value);
+        }
+        self.len += 3 + words;
+        Ok(())
+    }
+
+    ///Adds the end tag and the header, then hands the buffer to the firmware and checks the
+    ///response code
+    fn call(&mut self) -> Result<(), MailboxError> {
+        let len = self.len;
+        self.write(len, 0);
+        self.write(0, ((len + 1) * 4) as u32);
+        self.write(1, REQUEST);
+
+        let registers = unsafe { &mut *(MAILBOX_BASE as *mut Registers) };
+        let message = (self.words as usize as u32 & !0xF) | CHANNEL_PROPERTY;
+        let deadline = current_time() + TIMEOUT;
+        while registers.STATUS.read() & MAIL_FULL != 0 {
+            if current_time() > deadline {
+                return Err(MailboxError::Timeout);
+            }
+        }
+        registers.WRITE.write(message);
+        loop {
+            while registers.STATUS.read() & MAIL_EMPTY != 0 {
+                if current_time() > deadline {
+                    return Err(MailboxError::Timeout);
+                }
+            }
+            //Replies to other channels aren't for us
+            if registers.READ.read() == message {
+                break;
+            }
+        }
+
+        match self.read(1) {
+            RESPONSE_SUCCESS => Ok(()),
+            RESPONSE_ERROR => Err(MailboxError::RequestFailed),
+            _ => Err(MailboxError::NoResponse),
+        }
+    }
+
+    ///Checks the tag at the offset has been answered in full and hands it its values, returning
+    ///the offset of the next tag
+    fn pop(&self, offset: usize, tag: &mut Tag) -> Result<usize, MailboxError> {
+        let words = tag.words();
+        let length = self.read(offset + 2);
+        if length & TAG_RESPONSE == 0 {
+            return Err(MailboxError::TagNotAnswered(tag.id()));
+        }
+        if ((length & !TAG_RESPONSE) as usize) < response_bytes(tag) {
+            return Err(MailboxError::ShortResponse(tag.id()));
+        }
+        let mut values = [0; MAX_VALUE_WORDS];
+        for i in 0..words {
+            values[i] = self.read(offset + 3 + i);
+        }
+        tag.read_response(&values[..words]);
+        Ok(offset + 3 + words)
+    }
+}
+
+///Every tag here fills its whole value buffer in its response
+fn response_bytes(tag: &Tag) -> usize {
+    tag.words() * 4
+}
+
+///Sends the tags to the firmware in one request and fills in their responses
+pub fn request(tags: &mut [&mut Tag]) -> Result<(), MailboxError> {
+    let mut buffer = PropertyBuffer::new();
+    for tag in tags.iter() {
+        buffer.push(&**tag)?;
+    }
+    buffer.call()?;
+    let mut offset = 2;
+    for tag in tags.iter_mut() {
+        offset = buffer.pop(offset, &mut **tag)?;
+    }
+    Ok(())
+}
+
+///Sends a single tag
+pub fn request_one(tag: &mut Tag) -> Result<(), MailboxError> {
+    request(&mut [tag])
+}
+
+///Version of the firmware the Pi booted with
+#[derive(Debug, Default)]
+pub struct FirmwareRevision {
+    pub revision: u32,
+}
+
+impl FirmwareRevision {
+    pub fn new() -> FirmwareRevision {
+        FirmwareRevision::default()
+    }
+}
+
+impl Tag for FirmwareRevision {
+    fn id(&self) -> u32 {
+        0x0000_0001
+    }
+    fn words(&self) -> usize {
+        1
+    }
+    fn read_response(&mut self, values: &[u32]) {
+        self.revision = values[0];
+    }
+}
+
+///Board revision code, which says what model of Pi this is and how much memory it has. The
+///codes are listed at <https://www.raspberrypi.org/documentation/hardware/raspberrypi/revision-codes/>
+#[derive(Debug, Default)]
+pub struct BoardRevision {
+    pub revision: u32,
+}
+
+impl BoardRevision {
+    pub fn new() -> BoardRevision {
+        BoardRevision::default()
+    }
+}
+
+impl Tag for BoardRevision {
+    fn id(&self) -> u32 {
+        0x0001_0002
+    }
+    fn words(&self) -> usize {
+        1
+    }
+    fn read_response(&mut self, values: &[u32]) {
+        self.revision = values[0];
+    }
+}
+
+///The board's unique serial number
+#[derive(Debug, Default)]
+pub struct BoardSerial {
+    pub serial: u64,
+}
+
+impl BoardSerial {
+    pub fn new() -> BoardSerial {
+        BoardSerial::default()
+    }
+}
+
+impl Tag for BoardSerial {
+    fn id(&self) -> u32 {
+        0x0001_0004
+    }
+    fn words(&self) -> usize {
+        2
+    }
+    fn read_response(&mut self, values: &[u32]) {
+        self.serial = (values[1] as u64) << 32 | values[0] as u64;
+    }
+}
+
+///Which part of RAM the ARM or the GPU gets, set by gpu_mem in config.txt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryOwner {
+    Arm,
+    VideoCore,
+}
+
+///Base address and size of the memory the ARM or the GPU has
+#[derive(Debug)]
+pub struct Memory {
+    owner: MemoryOwner,
+    pub base: u32,
+    pub size: u32,
+}
+
+impl Memory {
+    pub fn new(owner: MemoryOwner) -> Memory {
+        Memory {
+            owner,
+            base: 0,
+            size: 0,
+        }
+    }
+}
+
+impl Tag for Memory {
+    fn id(&self) -> u32 {
+        match self.owner {
+            MemoryOwner::Arm => 0x0001_0005,
+            MemoryOwner::VideoCore => 0x0001_0006,
+        }
+    }
+    fn words(&self) -> usize {
+        2
+    }
+    fn read_response(&mut self, values: &[u32]) {
+        self.base = values[0];
+        self.size = values[1];
+    }
+}
+
+///The clocks the firmware can report the rate of
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockId {
+    Emmc = 1,
+    Uart = 2,
+    Arm = 3,
+    Core = 4,
+    V3d = 5,
+    H264 = 6,
+    Isp = 7,
+    Sdram = 8,
+    Pixel = 9,
+    Pwm = 10,
+}
+
+///Rate of one of the clocks in Hz, either what it is running at now or the most it can run at
+#[derive(Debug)]
+pub struct ClockRate {
+    clock: ClockId,
+    max: bool,
+    pub rate: u32,
+}
+
+impl ClockRate {
+    ///The rate the clock is running at now
+    pub fn new(clock: ClockId) -> ClockRate {
+        ClockRate {
+            clock,
+            max: false,
+            rate: 0,
+        }
+    }
+
+    ///The fastest the clock can be run
+    pub fn max(clock: ClockId) -> ClockRate {
+        ClockRate {
+            clock,
+            max: true,
+            rate: 0,
+        }
+    }
+}
+
+impl Tag for ClockRate {
+    fn id(&self) -> u32 {
+        match self.max {
+            false => 0x0003_0002,
+            true => 0x0003_0004,
+        }
+    }
+    fn words(&self) -> usize {
+        2
+    }
+    fn write_request(&self, values: &mut [u32]) {
+        values[0] = self.clock as u32;
+    }
+    fn read_response(&mut self, values: &[u32]) {
+        self.rate = values[1];
+    }
+}
+
+///SoC temperature in thousandths of a degree C, or the temperature the firmware starts slowing
+///the clocks down at
+#[derive(Debug, Default)]
+pub struct Temperature {
+    max: bool,
+    pub millidegrees: u32,
+}
+
+impl Temperature {
+    pub fn new() -> Temperature {
+        Temperature::default()
+    }
+
+    pub fn max() -> Temperature {
+        Temperature {
+            max: true,
+            millidegrees: 0,
+        }
+    }
+}
+
+impl Tag for Temperature {
+    fn id(&self) -> u32 {
+        match self.max {
+            false => 0x0003_0006,
+            true => 0x0003_000A,
+        }
+    }
+    fn words(&self) -> usize {
+        2
+    }
+    fn write_request(&self, values: &mut [u32]) {
+        //Temperature id 0 is the only one there is
+        values[0] = 0;
+    }
+    fn read_response(&mut self, values: &[u32]) {
+        self.millidegrees = values[1];
+    }
+}
+
+///Devices the firmware can switch the power to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerDevice {
+    SdCard = 0,
+    Uart0 = 1,
+    Uart1 = 2,
+    UsbHcd = 3,
+    I2c0 = 4,
+    I2c1 = 5,
+    I2c2 = 6,
+    Spi = 7,
+    Ccp2tx = 8,
+}
+
+///Gets or sets the power state of a device
+#[derive(Debug)]
+pub struct PowerState {
+    device: PowerDevice,
+    ///Some when setting, None when only asking
+    set: Option<bool>,
+    ///If the device is on, filled in by the response
+    pub on: bool,
+    ///If the firmware says the device doesn't exist
+    pub missing: bool,
+}
+
+impl PowerState {
+    ///Asks if the device is powered
+    pub fn new(device: PowerDevice) -> PowerState {
+        PowerState {
+            device,
+            set: None,
+            on: false,
+            missing: false,
+        }
+    }
+
+    ///Switches the device on or off, waiting for it to be stable before the firmware replies
+    pub fn set(device: PowerDevice, on: bool) -> PowerState {
+        PowerState {
+            device,
+            set: Some(on),
+            on: false,
+            missing: false,
+        }
+    }
+}
+
+impl Tag for PowerState {
+    fn id(&self) -> u32 {
+        match self.set {
+            None => 0x0002_0001,
+            Some(_) => 0x0002_8001,
+        }
+    }
+    fn words(&self) -> usize {
+        2
+    }
+    fn write_request(&self, values: &mut [u32]) {
+        values[0] = self.device as u32;
+        //Bit 0 is on, bit 1 is wait
+        if let Some(on) = self.set {
+            values[1] = on as u32 | 0b10;
+        }
+    }
+    fn read_response(&mut self, values: &[u32]) {
+        self.on = values[1] & 0b01 != 0;
+        self.missing = values[1] & 0b10 != 0;
+    }
+}
+
+///Framebuffer tags. The physical size is the resolution sent to the display, the virtual size
+///is the size of the buffer in memory, which can be bigger so part of it can be shown at a time
+#[derive(Debug)]
+pub struct DisplaySize {
+    virtual_size: bool,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl DisplaySize {
+    ///Sets the resolution of the display
+    pub fn physical(width: u32, height: u32) -> DisplaySize {
+        DisplaySize {
+            virtual_size: false,
+            width,
+            height,
+        }
+    }
+
+    ///Sets the size of the buffer in memory
+    pub fn virtual_size(width: u32, height: u32) -> DisplaySize {
+        DisplaySize {
+            virtual_size: true,
+            width,
+            height,
+        }
+    }
+}
+
+impl Tag for DisplaySize {
+    fn id(&self) -> u32 {
+        match self.virtual_size {
+            false => 0x0004_8003,
+            true => 0x0004_8004,
+        }
+    }
+    fn words(&self) -> usize {
+        2
+    }
+    fn write_request(&self, values: &mut [u32]) {
+        values[0] = self.width;
+        values[1] = self.height;
+    }
+    fn read_response(&mut self, values: &[u32]) {
+        self.width = values[0];
+        self.height = values[1];
+    }
+}
+
+///Sets the bits per pixel, the response is what the firmware actually chose
+#[derive(Debug)]
+pub struct Depth {
+    pub bits: u32,
+}
+
+impl Depth {
+    pub fn new(bits: u32) -> Depth {
+        Depth { bits }
+    }
+}
+
+impl Tag for Depth {
+    fn id(&self) -> u32 {
+        0x0004_8005
+    }
+    fn words(&self) -> usize {
+        1
+    }
+    fn write_request(&self, values: &mut [u32]) {
+        values[0] = self.bits;
+    }
+    fn read_response(&mut self, values: &[u32]) {
+        self.bits = values[0];
+    }
+}
+
+///Order of the colour channels in a pixel, from the most significant end
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColourOrder {
+    Bgr = 0,
+    Rgb = 1,
+}
+
+///Sets the colour order, not every firmware lets it be changed so check the response
+#[derive(Debug)]
+pub struct PixelOrder {
+    pub order: ColourOrder,
+}
+
+impl PixelOrder {
+    pub fn new(order: ColourOrder) -> PixelOrder {
+        PixelOrder { order }
+    }
+}
+
+impl Tag for PixelOrder {
+    fn id(&self) -> u32 {
+        0x0004_8006
+    }
+    fn words(&self) -> usize {
+        1
+    }
+    fn write_request(&self, values: &mut [u32]) {
+        values[0] = self.order as u32;
+    }
+    fn read_response(&mut self, values: &[u32]) {
+        self.order = match values[0] {
+            0 => ColourOrder::Bgr,
+            _ => ColourOrder::Rgb,
+        };
+    }
+}
+
+///Sets which part of the virtual buffer is shown on the display, in pixels from the top left
+#[derive(Debug)]
+pub struct VirtualOffset {
+    pub x: u32,
+    pub y: u32,
+}
+
+impl VirtualOffset {
+    pub fn new(x: u32, y: u32) -> VirtualOffset {
+        VirtualOffset { x, y }
+    }
+}
+
+impl Tag for VirtualOffset {
+    fn id(&self) -> u32 {
+        0x0004_8009
+    }
+    fn words(&self) -> usize {
+        2
+    }
+    fn write_request(&self, values: &mut [u32]) {
+        values[0] = self.x;
+        values[1] = self.y;
+    }
+    fn read_response(&mut self, values: &[u32]) {
+        self.x = values[0];
+        self.y = values[1];
+    }
+}
+
+///Asks the firmware to allocate the framebuffer with the settings from the other tags in the
+///same request. The base is a VideoCore bus address, see Framebuffer for turning it into one the
+///ARM can use
+#[derive(Debug)]
+pub struct AllocateBuffer {
+    alignment: u32,
+    pub base: u32,
+    pub size: u32,
+}
+
+impl AllocateBuffer {
+    ///Alignment of the buffer in bytes
+    pub fn new(alignment: u32) -> AllocateBuffer {
+        AllocateBuffer {
+            alignment,
+            base: 0,
+            size: 0,
+        }
+    }
+}
+
+impl Tag for AllocateBuffer {
+    fn id(&self) -> u32 {
+        0x0004_0001
+    }
+    fn words(&self) -> usize {
+        2
+    }
+    fn write_request(&self, values: &mut [u32]) {
+        values[0] = self.alignment;
+    }
+    fn read_response(&mut self, values: &[u32]) {
+        self.base = values[0];
+        self.size = values[1];
+    }
+}
+
+///Bytes from the start of one row of pixels to the start of the next, which can be more than
+///the width times the bytes per pixel
+#[derive(Debug, Default)]
+pub struct Pitch {
+    pub bytes: u32,
+}
+
+impl Pitch {
+    pub fn new() -> Pitch {
+        Pitch::default()
+    }
+}
+
+impl Tag for Pitch {
+    fn id(&self) -> u32 {
+        0x0004_0008
+    }
+    fn words(&self) -> usize {
+        1
+    }
+    fn read_response(&mut self, values: &[u32]) {
+        self.bytes = values[0];
+    }
+}
+
+///Asks the firmware how fast one of the clocks is running, in Hz
+pub fn clock_rate(clock: ClockId) -> Result<u32, MailboxError> {
+    let mut tag = ClockRate::new(clock);
+    request_one(&mut tag)?;
+    Ok(tag.rate)
+}
+
+pub fn board_revision() -> Result<u32, MailboxError> {
+    let mut tag = BoardRevision::new();
+    request_one(&mut tag)?;
+    Ok(tag.revision)
+}
+
+pub fn board_serial() -> Result<u64, MailboxError> {
+    let mut tag = BoardSerial::new();
+    request_one(&mut tag)?;
+    Ok(tag.serial)
+}
+
+///SoC temperature in thousandths of a degree C
+pub fn temperature() -> Result<u32, MailboxError> {
+    let mut tag = Temperature::new();
+    request_one(&mut tag)?;
+    Ok(tag.millidegrees)
+}
+
+///Switches the power to a device on or off, returning false if the firmware says the device
+///doesn't exist
+pub fn set_power(device: PowerDevice, on: bool) -> Result<bool, MailboxError> {
+    let mut tag = PowerState::set(device, on);
+    request_one(&mut tag)?;
+    Ok(!tag.missing)
+}
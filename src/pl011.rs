@@ -0,0 +1,403 @@
+/// Documentation for the hardware for the PL011 UART can be found on page 175 of the Broadcom
+/// manual, the ARM PrimeCell UART (PL011) Technical Reference Manual has the full details.
+///
+/// On the Pi 3 this UART is wired to the Bluetooth chip unless the firmware is told otherwise
+/// (dtoverlay=pi3-disable-bt or pi3-miniuart-bt in config.txt). QEMU's raspi3 machine connects
+/// its first -serial to this one.
+
+use common::UART0_BASE;
+use core::fmt;
+use gpio::{Alt, AltFunction, Gpio};
+use mailbox::{self, ClockId};
+use serial::{LineStatus, SerialPort};
+use timer::current_time;
+use uart::{ClockSource, ConfigError};
+use volatile::{ReadOnly, Volatile};
+
+///Register map from page 177 of the Broadcom manual
+///
+///This structure uses [repr(C)].
+///This means that the compiler is NOT free to reorder the fields of this structure for alignment
+///reasons, its very important the layout is exactly as ive defined it.
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    ///Data, the bottom 8 bits are the character and bits 11:8 the errors it was received with
+    DR: Volatile<u32>,
+    ///Receive status / error clear
+    RSRECR: Volatile<u32>,
+    _a: [u32; 4],
+    ///Flags
+    FR: ReadOnly<u32>,
+    _b: u32,
+    ///IrDA, not used on the Pi
+    ILPR: Volatile<u32>,
+    ///Integer and fractional baud rate divisor
+    IBRD: Volatile<u32>,
+    FBRD: Volatile<u32>,
+    ///Line control
+    LCRH: Volatile<u32>,
+    ///Control
+    CR: Volatile<u32>,
+    ///Interrupt FIFO level select
+    IFLS: Volatile<u32>,
+    ///Interrupt mask set/clear
+    IMSC: Volatile<u32>,
+    ///Raw and masked interrupt status
+    RIS: ReadOnly<u32>,
+    MIS: ReadOnly<u32>,
+    ///Interrupt clear
+    ICR: Volatile<u32>,
+    DMACR: Volatile<u32>,
+}
+
+///FR bits
+const FR_TXFE: u32 = 1 << 7;
+const FR_TXFF: u32 = 1 << 5;
+const FR_RXFE: u32 = 1 << 4;
+const FR_BUSY: u32 = 1 << 3;
+
+///Error bits that come with each received character in DR
+const DR_BREAK: u32 = 1 << 10;
+
+///RSRECR bits, the same errors but sticky until cleared
+const RSRECR_OE: u32 = 1 << 3;
+const RSRECR_BE: u32 = 1 << 2;
+
+///LCRH bits
+const LCRH_SPS: u32 = 1 << 7;
+const LCRH_FEN: u32 = 1 << 4;
+const LCRH_STP2: u32 = 1 << 3;
+const LCRH_EPS: u32 = 1 << 2;
+const LCRH_PEN: u32 = 1 << 1;
+const LCRH_BRK: u32 = 1 << 0;
+
+///CR bits
+const CR_RXE: u32 = 1 << 9;
+const CR_TXE: u32 = 1 << 8;
+const CR_UARTEN: u32 = 1 << 0;
+
+///Clock the firmware gives the PL011 by default (init_uart_clock in config.txt)
+const DEFAULT_CLOCK: u32 = 48_000_000;
+
+///Number of bits in each character
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+    ///Parity bit always 1
+    Mark,
+    ///Parity bit always 0
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+///Settings for the PL011, built with the builder pattern and applied with Pl011::configure
+///
+///x = Pl011Config::new().baud_rate(9600).parity(Parity::Even).stop_bits(StopBits::Two);
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pl011Config {
+    baud_rate: u32,
+    data_bits: DataBits,
+    parity: Parity,
+    stop_bits: StopBits,
+    fifos: bool,
+    clock_source: ClockSource,
+}
+
+impl Pl011Config {
+    ///Constructor, 115200 8N1 with the FIFOs on and the UART clock rate from the mailbox
+    pub fn new() -> Pl011Config {
+        Pl011Config {
+            baud_rate: 115200,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            fifos: true,
+            clock_source: ClockSource::Mailbox,
+        }
+    }
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+    pub fn data_bits(mut self, data_bits: DataBits) -> Self {
+        self.data_bits = data_bits;
+        self
+    }
+    pub fn parity(mut self, parity: Parity) -> Self {
+        self.parity = parity;
+        self
+    }
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> Self {
+        self.stop_bits = stop_bits;
+        self
+    }
+    ///Turn the 16 byte transmit and receive FIFOs on or off
+    pub fn fifos(mut self, fifos: bool) -> Self {
+        self.fifos = fifos;
+        self
+    }
+    ///Set where the UART clock rate comes from
+    pub fn clock_source(mut self, clock_source: ClockSource) -> Self {
+        self.clock_source = clock_source;
+        self
+    }
+}
+
+///Longest a new Pl011 waits for bytes left over from the last one to go out, in microseconds.
+///16 bytes take 17ms at 9600 baud, a UART that can't send at all mustn't hang the panic handler
+const DRAIN_MICROS: u64 = 50_000;
+
+///Waits for the transmitter to finish what it has queued, giving up after micros if there is a
+///limit. A disabled UART never sends what is left in its FIFO, so it isn't waited for at all
+fn drain(registers: &Registers, micros: Option<u64>) {
+    if registers.CR.read() & CR_UARTEN == 0 {
+        return;
+    }
+    let deadline = micros.map(|micros| current_time() + micros);
+    while registers.FR.read() & FR_TXFE == 0 || registers.FR.read() & FR_BUSY != 0 {
+        match deadline {
+            Some(deadline) if current_time() >= deadline => return,
+            _ => {}
+        }
+    }
+}
+
+///Claims one of the PL011's pins and switches it over to the UART
+fn claim_pin(pin: u8) -> Gpio<Alt> {
+    match Gpio::claim(pin, "pl011") {
+        Ok(gpio) => gpio.as_alt(AltFunction(0)),
+        Err(e) => panic!("PL011 can't start: {}", e),
+    }
+}
+
+///Takes one of the PL011's pins whoever holds it, for Pl011::steal
+fn steal_pin(pin: u8) -> Gpio<Alt> {
+    Gpio::steal(pin, "pl011").as_alt(AltFunction(0))
+}
+
+///Wrapper for the PL011 registers
+pub struct Pl011 {
+    registers: &'static mut Registers,
+    ///TX and RX, held so nothing else can take them while the device exists
+    _pins: [Gpio<Alt>; 2],
+    ///Timeout in milliseconds for the timed read functions, None waits forever
+    timeout: Option<u32>,
+    ///If a break condition has been received since take_break was last called, only set by
+    ///read_byte. Breaks that arrive while nobody is reading are still flagged in RSRECR
+    break_received: bool,
+}
+
+impl Pl011 {
+    ///Constructor, sets the device up as 115200 8N1 assuming the default 48MHz UART clock.
+    ///Use configure to work it out from the real clock rate or change the format.
+    ///Claims GPIO14 and 15, panicking if another driver holds them, and gives them back when the
+    ///Pl011 is dropped
+    pub fn new() -> Pl011 {
+        Pl011::start(claim_pin)
+    }
+
+    ///Constructor for the panic handler. Takes GPIO14 and 15 even if another driver holds them,
+    ///so a panic caused by the pins being taken can't panic again trying to report it
+    pub fn steal() -> Pl011 {
+        Pl011::start(steal_pin)
+    }
+
+    ///Sets the device up, getting hold of each pin with take_pin
+    fn start(take_pin: fn(u8) -> Gpio<Alt>) -> Pl011 {
+        let registers = unsafe { &mut *(UART0_BASE as *mut Registers) };
+
+        //Let whatever the last user queued go out first, then turn the UART off while it is set
+        //up, see page 185 of the Broadcom manual
+        drain(registers, Some(DRAIN_MICROS));
+        registers.CR.write(0);
+
+        //Claim the Gpio pins and set them to use AltFunction 0, see page 102 of Broadcom manual
+        let pins = [take_pin(14), take_pin(15)];
+
+        //Polled only, mask and clear every interrupt
+        registers.IMSC.write(0);
+        registers.ICR.write(0x7FF);
+
+        let mut pl011 = Pl011 {
+            registers,
+            _pins: pins,
+            timeout: None,
+            break_received: false,
+        };
+        let config = Pl011Config::new().clock_source(ClockSource::Fixed(DEFAULT_CLOCK));
+        pl011
+            .configure(&config)
+            .expect("115200 baud is always reachable from the default clock");
+        pl011
+    }
+
+    ///Applies the settings in the config, returning the baud rate actually achieved.
+    ///
+    ///The baud rate divisor is UART clock / (16 * baud rate) as a fixed point number with a 16 bit
+    ///integer part in IBRD and a 6 bit fractional part in FBRD, see page 183 of the Broadcom manual
+    pub fn configure(&mut self, config: &Pl011Config) -> Result<u32, ConfigError> {
+        let clock = match config.clock_source {
+            ClockSource::Mailbox => match mailbox::clock_rate(ClockId::Uart) {
+                Ok(clock) => clock,
+                Err(_) => return Err(ConfigError::ClockUnavailable),
+            },
+            ClockSource::Fixed(clock) => clock,
+        };
+        if config.baud_rate == 0 {
+            return Err(ConfigError::BaudRateOutOfRange);
+        }
+
+        //Divisor in 64ths, clock * 64 / (16 * baud), rounded to the nearest
+        let divisor = (4 * clock as u64 + config.baud_rate as u64 / 2) / config.baud_rate as u64;
+        let integer = divisor >> 6;
+        let fraction = divisor & 0x3F;
+        if integer < 1 || integer > 0xFFFF {
+            return Err(ConfigError::BaudRateOutOfRange);
+        }
+
+        let mut lcrh = match config.data_bits {
+            DataBits::Five => 0b00 << 5,
+            DataBits::Six => 0b01 << 5,
+            DataBits::Seven => 0b10 << 5,
+            DataBits::Eight => 0b11 << 5,
+        };
+        lcrh |= match config.parity {
+            Parity::None => 0,
+            Parity::Even => LCRH_PEN | LCRH_EPS,
+            Parity::Odd => LCRH_PEN,
+            Parity::Mark => LCRH_PEN | LCRH_SPS,
+            Parity::Space => LCRH_PEN | LCRH_SPS | LCRH_EPS,
+        };
+        if config.stop_bits == StopBits::Two {
+            lcrh |= LCRH_STP2;
+        }
+        if config.fifos {
+            lcrh |= LCRH_FEN;
+        }
+
+        //The UART has to be off and idle before the line control is changed, page 185
+        self.flush();
+        self.registers.CR.write(0);
+        self.registers.IBRD.write(integer as u32);
+        self.registers.FBRD.write(fraction as u32);
+        //Writing LCRH is what actually latches the new divisor
+        self.registers.LCRH.write(lcrh);
+        self.registers.CR.write(CR_UARTEN | CR_TXE | CR_RXE);
+
+        Ok((4 * clock as u64 / divisor) as u32)
+    }
+
+    ///Set a timeout in milliseconds for the timed read functions via builder pattern
+    pub fn with_timeout(mut self, timeout: u32) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    ///Checks if a break condition (the line held low for longer than a character) has been
+    ///received since the last call
+    pub fn take_break(&mut self) -> bool {
+        let out = self.break_received || self.registers.RSRECR.read() & RSRECR_BE != 0;
+        self.break_received = false;
+        //Any write to RSRECR clears the error flags
+        self.registers.RSRECR.write(0);
+        out
+    }
+}
+
+impl SerialPort for Pl011 {
+    ///Writes a u8 byte to the device, blocking until there is space in the transmit FIFO
+    fn write_byte(&mut self, b: u8) {
+        while self.registers.FR.read() & FR_TXFF != 0 {}
+        self.registers.DR.write(b as u32);
+    }
+
+    fn try_write_byte(&mut self, b: u8) -> Result<(), ()> {
+        if self.registers.FR.read() & FR_TXFF != 0 {
+            return Err(());
+        }
+        self.registers.DR.write(b as u32);
+        Ok(())
+    }
+
+    ///If the receive FIFO isn't empty there is data waiting to be read out
+    fn has_byte(&self) -> bool {
+        self.registers.FR.read() & FR_RXFE == 0
+    }
+
+    ///Read the incoming data from the device, waiting for it to arrive.
+    ///A break shows up as a zero byte with the break bit set, which is remembered for take_break
+    fn read_byte(&mut self) -> u8 {
+        while !self.has_byte() {}
+        let data = self.registers.DR.read();
+        if data & DR_BREAK != 0 {
+            self.break_received = true;
+        }
+        data as u8
+    }
+
+    ///Blocks until every byte has been sent and the transmitter is idle. Returns straight away if
+    ///the UART is disabled, it would never finish
+    fn flush(&mut self) {
+        drain(self.registers, None);
+    }
+
+    ///Built from FR and RSRECR, see pages 179 and 181 of the Broadcom manual
+    fn line_status(&self) -> LineStatus {
+        let fr = self.registers.FR.read();
+        let rsrecr = self.registers.RSRECR.read();
+        LineStatus {
+            data_ready: fr & FR_RXFE == 0,
+            can_transmit: fr & FR_TXFF == 0,
+            transmitter_idle: fr & FR_TXFE != 0 && fr & FR_BUSY == 0,
+            overrun: rsrecr & RSRECR_OE != 0,
+            break_received: self.break_received || rsrecr & RSRECR_BE != 0,
+        }
+    }
+
+    ///Holds the transmit line low while on is true, sending a break
+    fn send_break(&mut self, on: bool) {
+        let lcrh = self.registers.LCRH.read();
+        self.registers.LCRH.write(match on {
+            true => lcrh | LCRH_BRK,
+            false => lcrh & !LCRH_BRK,
+        });
+    }
+
+    fn timeout(&self) -> Option<u32> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: u32) {
+        self.timeout = Some(timeout);
+    }
+
+    fn clear_timeout(&mut self) {
+        self.timeout = None;
+    }
+}
+
+///Implement write for Pl011, the same as for Uart
+impl fmt::Write for Pl011 {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for i in s.chars() {
+            self.write_byte(i as u8)
+        }
+        Ok(())
+    }
+}
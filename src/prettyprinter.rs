@@ -1,78 +1,201 @@
-use core::fmt::Write;
+use core::fmt::{self, Write};
+use framebuffer::FramebufferConsole;
+use pl011::Pl011;
 use uart::Uart;
 
-///ANSII Escape code 
+///ANSII Escape code
 pub const ESC: char = 27 as char;
 
-///FgColour wrapper, wraps a str, for typechecking
-pub struct FgColour<'a>(&'a str);
+///A colour a terminal can show. The first sixteen are the terminal's own palette so they look
+///however it has been set up, Indexed picks from the 256 colour palette (the first sixteen of
+///which are these again) and Rgb is 24 bit colour, which not every terminal supports
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnsiColour {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    BrightBlack,
+    BrightRed,
+    BrightGreen,
+    BrightYellow,
+    BrightBlue,
+    BrightMagenta,
+    BrightCyan,
+    BrightWhite,
+    ///Whatever the terminal uses when nothing has been set
+    Default,
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+///FgColour wrapper, wraps a colour, for typechecking
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FgColour(pub AnsiColour);
 ///BgColour, see above
-pub struct BgColour<'a>(&'a str);
-
-///ANSII colour codes (need to be treated as chars/strings as this is the value the terminal reads,
-///like a human would, not the ASCI code a computer would read
-pub const FG_CLEAR: FgColour = FgColour("0");
-pub const FG_BLACK: FgColour = FgColour("30");
-pub const FG_RED: FgColour = FgColour("31");
-pub const FG_GREEN: FgColour = FgColour("32");
-pub const FG_YELLOW: FgColour = FgColour("33");
-pub const FG_BLUE: FgColour = FgColour("34");
-pub const FG_WHITE: FgColour = FgColour("37");
-
-pub const BG_CLEAR: BgColour = BgColour("0");
-pub const BG_BLACK: BgColour = BgColour("40");
-pub const BG_RED: BgColour = BgColour("41");
-pub const BG_GREEN: BgColour = BgColour("42");
-pub const BG_YELLOW: BgColour = BgColour("43");
-pub const BG_BLUE: BgColour = BgColour("44");
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BgColour(pub AnsiColour);
+
+///ANSII colours, the CLEAR ones go back to the terminal's default
+pub const FG_CLEAR: FgColour = FgColour(AnsiColour::Default);
+pub const FG_BLACK: FgColour = FgColour(AnsiColour::Black);
+pub const FG_RED: FgColour = FgColour(AnsiColour::Red);
+pub const FG_GREEN: FgColour = FgColour(AnsiColour::Green);
+pub const FG_YELLOW: FgColour = FgColour(AnsiColour::Yellow);
+pub const FG_BLUE: FgColour = FgColour(AnsiColour::Blue);
+pub const FG_MAGENTA: FgColour = FgColour(AnsiColour::Magenta);
+pub const FG_CYAN: FgColour = FgColour(AnsiColour::Cyan);
+pub const FG_WHITE: FgColour = FgColour(AnsiColour::White);
+
+pub const BG_CLEAR: BgColour = BgColour(AnsiColour::Default);
+pub const BG_BLACK: BgColour = BgColour(AnsiColour::Black);
+pub const BG_RED: BgColour = BgColour(AnsiColour::Red);
+pub const BG_GREEN: BgColour = BgColour(AnsiColour::Green);
+pub const BG_YELLOW: BgColour = BgColour(AnsiColour::Yellow);
+pub const BG_BLUE: BgColour = BgColour(AnsiColour::Blue);
+pub const BG_MAGENTA: BgColour = BgColour(AnsiColour::Magenta);
+pub const BG_CYAN: BgColour = BgColour(AnsiColour::Cyan);
+pub const BG_WHITE: BgColour = BgColour(AnsiColour::White);
+
+///How much of the line or display an erase clears, the cursor's own cell is always included
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Erase {
+    ToEnd = 0,
+    ToStart = 1,
+    All = 2,
+}
 
 ///Implement the AnsiPrettyPrinter trait for my Uart device. Uart already implements the required
 ///Write trait. There are no abstract methods in this trait so there's no code required to
 ///implement it
 impl AnsiPrettyPrinter for Uart {}
 
+///Same for the PL011
+impl AnsiPrettyPrinter for Pl011 {}
+
+///And the framebuffer console, which reads the escape codes back itself
+impl AnsiPrettyPrinter for FramebufferConsole {}
+
+///Writes a control sequence, ESC [ then the numbers and the letter. Errors are dropped, there's
+///nowhere to report them
+fn csi<W: Write + ?Sized>(w: &mut W, params: fmt::Arguments, command: char) {
+    let _ = write!(w, "{}[{}{}", ESC, params, command);
+}
+
+///Sets a colour with an ESC [ m sequence. base is 30 for the foreground and 40 for the
+///background, the bright colours are 60 further on and base + 8 takes a palette index or red,
+///green and blue after it
+fn set_colour<W: Write + ?Sized>(w: &mut W, colour: AnsiColour, base: u8) {
+    let offset = match colour {
+        AnsiColour::Black => 0,
+        AnsiColour::Red => 1,
+        AnsiColour::Green => 2,
+        AnsiColour::Yellow => 3,
+        AnsiColour::Blue => 4,
+        AnsiColour::Magenta => 5,
+        AnsiColour::Cyan => 6,
+        AnsiColour::White => 7,
+        AnsiColour::Default => 9,
+        AnsiColour::BrightBlack => 60,
+        AnsiColour::BrightRed => 61,
+        AnsiColour::BrightGreen => 62,
+        AnsiColour::BrightYellow => 63,
+        AnsiColour::BrightBlue => 64,
+        AnsiColour::BrightMagenta => 65,
+        AnsiColour::BrightCyan => 66,
+        AnsiColour::BrightWhite => 67,
+        AnsiColour::Indexed(n) => return csi(w, format_args!("{};5;{}", base + 8, n), 'm'),
+        AnsiColour::Rgb(r, g, b) => {
+            return csi(w, format_args!("{};2;{};{};{}", base + 8, r, g, b), 'm')
+        }
+    };
+    csi(w, format_args!("{}", base + offset), 'm');
+}
+
 ///Trait for extending the Write trait with some more actions, incase of adding more output devices
-///later
+///later. Rows and columns count from 0 at the top left, the terminal counts from 1 so they are
+///converted when sent
 pub trait AnsiPrettyPrinter: Write {
+    ///Resets the terminal, clearing the screen and every setting
     fn clr(&mut self) {
-        self.write_char(ESC);
-        self.write_char('c');
+        let _ = write!(self, "{}c", ESC);
     }
 
     fn set_fg_colour(&mut self, fg: FgColour) {
-        self.write_char(ESC);
-        self.write_char('[');
-        self.write_str(fg.0);
-        self.write_char('m');
+        set_colour(self, fg.0, 30);
     }
     fn set_bg_colour(&mut self, bg: BgColour) {
-        self.write_char(ESC);
-        self.write_char('[');
-        self.write_str(bg.0);
-        self.write_char('m');
-    }
-    fn move_cursor_right(&mut self, n: &str) {
-        self.write_char(ESC);
-        self.write_char('[');
-        self.write_str(n);
-        self.write_char('C');
-    }
-    fn move_cursor_left(&mut self, n: &str) {
-        self.write_char(ESC);
-        self.write_char('[');
-        self.write_str(n);
-        self.write_char('D');
-    }
-    fn move_cursor_up(&mut self, n: &str) {
-        self.write_char(ESC);
-        self.write_char('[');
-        self.write_str(n);
-        self.write_char('A');
-    }
-    fn move_cursor_down(&mut self, n: &str) {
-        self.write_char(ESC);
-        self.write_char('[');
-        self.write_str(n);
-        self.write_char('B');
+        set_colour(self, bg.0, 40);
+    }
+
+    ///Puts the colours, bold, underline and reverse back to the defaults
+    fn reset_attributes(&mut self) {
+        csi(self, format_args!("0"), 'm');
+    }
+    fn set_bold(&mut self, on: bool) {
+        csi(self, format_args!("{}", if on { 1 } else { 22 }), 'm');
+    }
+    fn set_underline(&mut self, on: bool) {
+        csi(self, format_args!("{}", if on { 4 } else { 24 }), 'm');
+    }
+    ///Swaps the foreground and background colours
+    fn set_reverse(&mut self, on: bool) {
+        csi(self, format_args!("{}", if on { 7 } else { 27 }), 'm');
+    }
+
+    fn move_cursor_right(&mut self, n: u16) {
+        csi(self, format_args!("{}", n), 'C');
+    }
+    fn move_cursor_left(&mut self, n: u16) {
+        csi(self, format_args!("{}", n), 'D');
+    }
+    fn move_cursor_up(&mut self, n: u16) {
+        csi(self, format_args!("{}", n), 'A');
+    }
+    fn move_cursor_down(&mut self, n: u16) {
+        csi(self, format_args!("{}", n), 'B');
+    }
+    fn move_cursor_to(&mut self, row: u16, column: u16) {
+        csi(self, format_args!("{};{}", row as u32 + 1, column as u32 + 1), 'H');
+    }
+
+    ///Remembers the cursor position, colours and attributes for restore_cursor
+    fn save_cursor(&mut self) {
+        let _ = write!(self, "{}7", ESC);
+    }
+    fn restore_cursor(&mut self) {
+        let _ = write!(self, "{}8", ESC);
+    }
+
+    ///Clears part of the screen without moving the cursor
+    fn erase_display(&mut self, erase: Erase) {
+        csi(self, format_args!("{}", erase as u8), 'J');
+    }
+    ///Clears part of the cursor's row without moving the cursor
+    fn erase_line(&mut self, erase: Erase) {
+        csi(self, format_args!("{}", erase as u8), 'K');
+    }
+
+    ///Only scrolls the rows from top to bottom, both included, the rest of the screen stays put.
+    ///Moves the cursor to the top left
+    fn set_scroll_region(&mut self, top: u16, bottom: u16) {
+        csi(self, format_args!("{};{}", top as u32 + 1, bottom as u32 + 1), 'r');
+    }
+    ///Scrolls the whole screen again, also moving the cursor to the top left
+    fn reset_scroll_region(&mut self) {
+        csi(self, format_args!(""), 'r');
+    }
+
+    ///Switches to a blank second screen, saving the cursor, so a full screen program can leave
+    ///the shell as it was when it finishes
+    fn enter_alternate_screen(&mut self) {
+        csi(self, format_args!("?1049"), 'h');
+    }
+    fn leave_alternate_screen(&mut self) {
+        csi(self, format_args!("?1049"), 'l');
     }
 }
@@ -0,0 +1,267 @@
+/// Documentation for the PWM hardware can be found on page 138 of the Broadcom manual.
+///
+/// There are two channels sharing one clock from the clock manager. Each channel counts up to
+/// its range and puts out its data value's worth of high clock cycles in that time, so the output
+/// frequency is clock / range and the duty cycle is data / range.
+///
+/// The PWM clock is started once at 9.6MHz (the 19.2MHz oscillator divided by 2) and left alone,
+/// so setting the frequency of one channel can't change the other.
+
+use arch::without_interrupts;
+use clock_manager::{self, Clock, Source};
+use common::PWM_BASE;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use gpio::{Alt, AltFunction, Gpio, PinError};
+use volatile::{ReadOnly, Volatile};
+
+///Register map from page 141 of the Broadcom manual
+///
+///This structure uses [repr(C)].
+///This means that the compiler is NOT free to reorder the fields of this structure for alignment
+///reasons, its very important the layout is exactly as ive defined it.
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    ///Control
+    CTL: Volatile<u32>,
+    ///Status
+    STA: ReadOnly<u32>,
+    ///DMA configuration
+    DMAC: Volatile<u32>,
+    _a: u32,
+    ///Channel 1 range and data
+    RNG1: Volatile<u32>,
+    DAT1: Volatile<u32>,
+    ///FIFO input, shared by both channels
+    FIF1: Volatile<u32>,
+    _b: u32,
+    ///Channel 2 range and data
+    RNG2: Volatile<u32>,
+    DAT2: Volatile<u32>,
+}
+
+///CTL bits for channel 1, channel 2's are the same 8 bits higher
+const CTL_PWEN: u32 = 1 << 0;
+const CTL_MODE: u32 = 1 << 1;
+const CTL_POLA: u32 = 1 << 4;
+const CTL_USEF: u32 = 1 << 5;
+const CTL_MSEN: u32 = 1 << 7;
+
+///Divisor from the oscillator to the PWM clock
+const CLOCK_DIVISOR: u32 = 2;
+
+///Frequency of the PWM clock once started
+const CLOCK_HZ: u32 = clock_manager::OSCILLATOR_HZ / CLOCK_DIVISOR;
+
+///Set once the PWM clock has been started
+static CLOCK_STARTED: AtomicBool = AtomicBool::new(false);
+
+///If each channel has been handed out
+static mut CHANNEL_OWNED: [bool; 2] = [false; 2];
+
+///The two PWM channels, called PWM0 and PWM1 in the pin function table on page 102
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    One = 0,
+    Two = 1,
+}
+
+///How the high clock cycles are spread through each period
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    ///All the high cycles in one block at the start of the period, a normal PWM signal with a
+    ///steady frequency. What servos and most other devices expect
+    MarkSpace,
+    ///The high cycles spread as evenly as possible through the period. The output switches
+    ///much faster for the same duty cycle, which is easier to smooth into a steady level for
+    ///dimming LEDs or making an analogue voltage with an RC filter
+    Balanced,
+}
+
+///Reasons a PWM channel can't be set up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PwmError {
+    ///Only GPIO12 and 18 (channel 1) and GPIO13 and 19 (channel 2) have a PWM function
+    NotPwmPin(u8),
+    ///The pin is owned by something else
+    Pin(PinError),
+    ///The channel is already driving another pin
+    ChannelInUse(Channel),
+    ///The frequency is 0 or higher than half the PWM clock
+    FrequencyOutOfRange,
+}
+
+impl From<PinError> for PwmError {
+    fn from(e: PinError) -> PwmError {
+        PwmError::Pin(e)
+    }
+}
+
+impl fmt::Display for PwmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PwmError::NotPwmPin(pin) => write!(f, "GPIO{} has no PWM function", pin),
+            PwmError::Pin(e) => write!(f, "{}", e),
+            PwmError::ChannelInUse(channel) => write!(f, "PWM channel {:?} is in use", channel),
+            PwmError::FrequencyOutOfRange => write!(f, "PWM frequency out of range"),
+        }
+    }
+}
+
+///The channel and alt function that puts it on each PWM capable pin, page 102
+fn pin_function(pin: u8) -> Result<(Channel, AltFunction), PwmError> {
+    match pin {
+        12 => Ok((Channel::One, AltFunction(0))),
+        13 => Ok((Channel::Two, AltFunction(0))),
+        18 => Ok((Channel::One, AltFunction(5))),
+        19 => Ok((Channel::Two, AltFunction(5))),
+        _ => Err(PwmError::NotPwmPin(pin)),
+    }
+}
+
+///Marks the channel as owned, failing if it already is
+fn claim_channel(channel: Channel) -> Result<(), PwmError> {
+    without_interrupts(|| unsafe {
+        if CHANNEL_OWNED[channel as usize] {
+            return Err(PwmError::ChannelInUse(channel));
+        }
+        CHANNEL_OWNED[channel as usize] = true;
+        Ok(())
+    })
+}
+
+///Starts the PWM clock if this is the first channel to be set up
+fn start_clock() {
+    if !CLOCK_STARTED.load(Ordering::SeqCst) {
+        clock_manager::start(Clock::Pwm, Source::Oscillator, CLOCK_DIVISOR)
+            .expect("the PWM clock divisor is always in range");
+        CLOCK_STARTED.store(true, Ordering::SeqCst);
+    }
+}
+
+///One PWM channel driving one pin. The channel stops and the pin is given back when dropped
+pub struct PwmChannel {
+    registers: &'static mut Registers,
+    channel: Channel,
+    ///Held so nothing else can take the pin while the channel is using it
+    _pin: Gpio<Alt>,
+    ///Clock cycles per period
+    range: u32,
+    ///Duty cycle in thousandths, kept so it can be reapplied when the range changes
+    duty: u32,
+}
+
+impl PwmChannel {
+    ///Claims the pin and its channel and starts it at 1kHz in mark-space mode with 0% duty
+    pub fn claim(pin: u8, owner: &'static str) -> Result<PwmChannel, PwmError> {
+        let (channel, function) = pin_function(pin)?;
+        let gpio = Gpio::claim(pin, owner)?;
+        claim_channel(channel)?;
+        start_clock();
+
+        let mut pwm = PwmChannel {
+            registers: unsafe { &mut *(PWM_BASE as *mut Registers) },
+            channel,
+            _pin: gpio.as_alt(function),
+            range: CLOCK_HZ / 1000,
+            duty: 0,
+        };
+        //PWM mode from the data register, not the serialiser or FIFO, normal polarity
+        pwm.update_ctl(CTL_MODE | CTL_USEF | CTL_POLA, false);
+        pwm.set_mode(Mode::MarkSpace);
+        pwm.write_range();
+        pwm.update_ctl(CTL_PWEN, true);
+        Ok(pwm)
+    }
+
+    ///Set the mode via builder pattern
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.set_mode(mode);
+        self
+    }
+
+    ///Sets or clears this channel's copy of the CTL bits, leaving the other channel alone
+    fn update_ctl(&mut self, bits: u32, on: bool) {
+        let bits = bits << (8 * self.channel as u32);
+        without_interrupts(|| {
+            let ctl = self.registers.CTL.read();
+            self.registers.CTL.write(match on {
+                true => ctl | bits,
+                false => ctl & !bits,
+            });
+        });
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.update_ctl(CTL_MSEN, mode == Mode::MarkSpace);
+    }
+
+    ///Swaps high and low, so the duty cycle sets the low time
+    pub fn set_inverted(&mut self, inverted: bool) {
+        self.update_ctl(CTL_POLA, inverted);
+    }
+
+    ///Sets the frequency in Hz, returning the frequency actually achieved.
+    ///Higher frequencies have fewer clock cycles per period so fewer steps of duty cycle, at
+    ///9.6kHz there are 1000 and at 4.8MHz only 2. The duty cycle is kept the same
+    pub fn set_frequency(&mut self, hz: u32) -> Result<u32, PwmError> {
+        if hz == 0 || hz > CLOCK_HZ / 2 {
+            return Err(PwmError::FrequencyOutOfRange);
+        }
+        self.range = CLOCK_HZ / hz;
+        self.write_range();
+        Ok(CLOCK_HZ / self.range)
+    }
+
+    ///The frequency the channel is running at in Hz
+    pub fn frequency(&self) -> u32 {
+        CLOCK_HZ / self.range
+    }
+
+    ///Sets the duty cycle in thousandths of the period, 0 is always low and 1000 always high
+    pub fn set_duty(&mut self, permille: u32) {
+        self.duty = permille.min(1000);
+        let data = (self.range as u64 * self.duty as u64 / 1000) as u32;
+        self.write_data(data);
+    }
+
+    ///The duty cycle in thousandths of the period
+    pub fn duty(&self) -> u32 {
+        self.duty
+    }
+
+    ///Sets the high time of each period in microseconds, for servos which are positioned by a
+    ///pulse of 1 to 2ms every 20ms. Pulses longer than the period are clamped to it
+    pub fn set_pulse_micros(&mut self, micros: u32) {
+        let data = (CLOCK_HZ as u64 * micros as u64 / 1_000_000).min(self.range as u64) as u32;
+        self.duty = (data as u64 * 1000 / self.range as u64) as u32;
+        self.write_data(data);
+    }
+
+    fn write_range(&mut self) {
+        match self.channel {
+            Channel::One => self.registers.RNG1.write(self.range),
+            Channel::Two => self.registers.RNG2.write(self.range),
+        }
+        let duty = self.duty;
+        self.set_duty(duty);
+    }
+
+    fn write_data(&mut self, data: u32) {
+        match self.channel {
+            Channel::One => self.registers.DAT1.write(data),
+            Channel::Two => self.registers.DAT2.write(data),
+        }
+    }
+}
+
+///Stops the channel and hands it back, the pin is released by its own drop
+impl Drop for PwmChannel {
+    fn drop(&mut self) {
+        self.update_ctl(CTL_PWEN, false);
+        unsafe {
+            CHANNEL_OWNED[self.channel as usize] = false;
+        }
+    }
+}
@@ -0,0 +1,77 @@
+/// Fixed size byte queue shared between an interrupt handler and the rest of the kernel
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+///Number of slots, one is always left empty to tell a full buffer from an empty one
+const SIZE: usize = 256;
+
+///A single producer, single consumer ring buffer.
+///
+///It is lock free: the producer only ever writes head and the consumer only ever writes tail, so
+///one side can be an interrupt handler without having to mask interrupts on the other side.
+///Only plain atomic loads and stores are used, the exclusive load/store instructions that
+///read-modify-write atomics compile to don't work with the caches off.
+pub struct RingBuffer {
+    buffer: UnsafeCell<[u8; SIZE]>,
+    ///Index of the next slot the producer will write
+    head: AtomicUsize,
+    ///Index of the next slot the consumer will read
+    tail: AtomicUsize,
+}
+
+///The UnsafeCell makes this !Sync by default, which would stop it being put in a static.
+///This is safe as long as there's only one producer and one consumer, as documented above
+unsafe impl Sync for RingBuffer {}
+
+impl RingBuffer {
+    ///Constructor, const so it can be used to initialise a static
+    pub const fn new() -> RingBuffer {
+        RingBuffer {
+            buffer: UnsafeCell::new([0; SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    ///Adds a byte to the back of the queue, fails if the queue is full. Producer side only
+    pub fn push(&self, b: u8) -> Result<(), ()> {
+        let head = self.head.load(Ordering::Relaxed);
+        let next = (head + 1) % SIZE;
+        if next == self.tail.load(Ordering::Acquire) {
+            return Err(());
+        }
+        unsafe {
+            (*self.buffer.get())[head] = b;
+        }
+        //Release makes sure the byte is written before the consumer can see the new head
+        self.head.store(next, Ordering::Release);
+        Ok(())
+    }
+
+    ///Takes a byte off the front of the queue if there is one. Consumer side only
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let b = unsafe { (*self.buffer.get())[tail] };
+        self.tail.store((tail + 1) % SIZE, Ordering::Release);
+        Some(b)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+
+    pub fn is_full(&self) -> bool {
+        (self.head.load(Ordering::Acquire) + 1) % SIZE == self.tail.load(Ordering::Acquire)
+    }
+
+    ///Number of bytes waiting in the queue
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        (head + SIZE - tail) % SIZE
+    }
+}
@@ -0,0 +1,347 @@
+/// Common interface to the UARTs and the registry of which one is the console
+
+use core::fmt;
+use framebuffer::FramebufferConsole;
+use pl011::Pl011;
+use prettyprinter::AnsiPrettyPrinter;
+use stdio::{stdin, stdout};
+use timer::current_time;
+use uart::Uart;
+
+///Error returned by the timed read functions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadError {
+    ///Nothing arrived before the timeout ran out
+    Timeout,
+    ///The buffer filled up before the delimiter arrived
+    BufferFull,
+}
+
+///Snapshot of the state of the line, read from the device's status registers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineStatus {
+    ///There is at least one byte waiting to be read
+    pub data_ready: bool,
+    ///A byte written now would be accepted without blocking
+    pub can_transmit: bool,
+    ///Everything written has been sent and the transmitter is idle
+    pub transmitter_idle: bool,
+    ///Received bytes have been lost because nobody read them in time
+    pub overrun: bool,
+    ///A break condition has been received, always false on devices that can't detect one
+    pub break_received: bool,
+}
+
+///Trait implemented by every UART so the console, stdio and panic code don't care which one
+///they are talking to. Only the byte level functions need implementing, the rest are built on them
+pub trait SerialPort: AnsiPrettyPrinter {
+    ///Writes a byte, blocking until the device can take it
+    fn write_byte(&mut self, b: u8);
+
+    ///Writes a byte only if the device can take it right now
+    fn try_write_byte(&mut self, b: u8) -> Result<(), ()>;
+
+    ///Checks if there is data waiting to be read
+    fn has_byte(&self) -> bool;
+
+    ///Reads a byte, blocking until one arrives
+    fn read_byte(&mut self) -> u8;
+
+    ///Blocks until everything written has been sent
+    fn flush(&mut self);
+
+    fn line_status(&self) -> LineStatus;
+
+    ///Holds the transmit line low while on is true, sending a break
+    fn send_break(&mut self, on: bool);
+
+    ///Timeout in milliseconds for the timed read functions, None waits forever
+    fn timeout(&self) -> Option<u32>;
+
+    ///Set a timeout in milliseconds for the timed read functions
+    fn set_timeout(&mut self, timeout: u32);
+
+    ///Remove the timeout, the timed read functions will wait forever
+    fn clear_timeout(&mut self);
+
+    ///Reads a byte only if there is one waiting
+    fn try_read_byte(&mut self) -> Option<u8> {
+        match self.has_byte() {
+            true => Some(self.read_byte()),
+            false => None,
+        }
+    }
+
+    ///Reads one byte, giving up with Err(ReadError::Timeout) if nothing arrives within the timeout
+    fn read_byte_timeout(&mut self) -> Result<u8, ReadError> {
+        let deadline = deadline(self.timeout());
+        read_byte_before(self, deadline)
+    }
+
+    ///Fills the whole buffer. The timeout covers the entire read, not each byte, so a host that
+    ///sends a trickle of bytes can't keep us waiting forever
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), ReadError> {
+        let deadline = deadline(self.timeout());
+        for slot in buf.iter_mut() {
+            *slot = read_byte_before(self, deadline)?;
+        }
+        Ok(())
+    }
+
+    ///Reads into the buffer until the delimiter arrives, returning the number of bytes read
+    ///including the delimiter. Like read_exact the timeout covers the entire read
+    fn read_until(&mut self, delimiter: u8, buf: &mut [u8]) -> Result<usize, ReadError> {
+        let deadline = deadline(self.timeout());
+        for i in 0..buf.len() {
+            let b = read_byte_before(self, deadline)?;
+            buf[i] = b;
+            if b == delimiter {
+                return Ok(i + 1);
+            }
+        }
+        Err(ReadError::BufferFull)
+    }
+
+    ///Pushes the data onto stdin directly
+    fn read_to_stdin(&mut self) {
+        //Get new stdin handle
+        let mut stdin = stdin().unwrap();
+        while self.has_byte() {
+            let b = self.read_byte();
+            stdin.push(b).unwrap();
+        }
+    }
+
+    ///Write all data in stdout to the device then clear it
+    fn flush_stdout(&mut self) {
+        let mut stdout = stdout().unwrap();
+        stdout.into_iter().for_each(|&x| self.write_byte(x));
+        stdout.clear();
+    }
+}
+
+///Works out when a timed read started now has to give up, in microseconds
+fn deadline(timeout: Option<u32>) -> Option<u64> {
+    timeout.map(|timeout| current_time() + timeout as u64 * 1000)
+}
+
+///Waits for a byte until the deadline passes
+fn read_byte_before<S: SerialPort + ?Sized>(
+    port: &mut S,
+    deadline: Option<u64>,
+) -> Result<u8, ReadError> {
+    while !port.has_byte() {
+        if let Some(deadline) = deadline {
+            if current_time() >= deadline {
+                return Err(ReadError::Timeout);
+            }
+        }
+    }
+    Ok(port.read_byte())
+}
+
+///The UARTs that can be the console
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleDevice {
+    MiniUart,
+    Pl011,
+}
+
+///The console device itself, owned by the registry once it has been chosen
+pub enum Console {
+    MiniUart(Uart),
+    Pl011(Pl011),
+}
+
+impl From<Uart> for Console {
+    fn from(uart: Uart) -> Console {
+        Console::MiniUart(uart)
+    }
+}
+
+impl From<Pl011> for Console {
+    fn from(pl011: Pl011) -> Console {
+        Console::Pl011(pl011)
+    }
+}
+
+impl Console {
+    fn device(&mut self) -> &mut SerialPort {
+        match *self {
+            Console::MiniUart(ref mut uart) => uart,
+            Console::Pl011(ref mut pl011) => pl011,
+        }
+    }
+
+    fn device_ref(&self) -> &SerialPort {
+        match *self {
+            Console::MiniUart(ref uart) => uart,
+            Console::Pl011(ref pl011) => pl011,
+        }
+    }
+}
+
+///Everything written to the console is copied to the mirror as well, reading only comes from the
+///device
+impl SerialPort for Console {
+    fn write_byte(&mut self, b: u8) {
+        self.device().write_byte(b);
+        with_mirror(|mirror| mirror.write_byte(b));
+    }
+
+    fn try_write_byte(&mut self, b: u8) -> Result<(), ()> {
+        self.device().try_write_byte(b)?;
+        with_mirror(|mirror| mirror.write_byte(b));
+        Ok(())
+    }
+
+    fn has_byte(&self) -> bool {
+        self.device_ref().has_byte()
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        self.device().read_byte()
+    }
+
+    fn flush(&mut self) {
+        self.device().flush()
+    }
+
+    fn line_status(&self) -> LineStatus {
+        self.device_ref().line_status()
+    }
+
+    fn send_break(&mut self, on: bool) {
+        self.device().send_break(on)
+    }
+
+    fn timeout(&self) -> Option<u32> {
+        self.device_ref().timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: u32) {
+        self.device().set_timeout(timeout)
+    }
+
+    fn clear_timeout(&mut self) {
+        self.device().clear_timeout()
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        with_mirror(|mirror| {
+            let _ = fmt::Write::write_str(mirror, s);
+        });
+        fmt::Write::write_str(self.device(), s)
+    }
+}
+
+impl AnsiPrettyPrinter for Console {}
+
+///Statically allocated storage for the console device
+static mut CONSOLE: Option<Console> = None;
+
+///Statically allocated storage for the screen the console is mirrored on
+static mut MIRROR: Option<FramebufferConsole> = None;
+
+///Copies everything written to the console onto a framebuffer console too, so the shell shows
+///up on HDMI as well as the UART
+pub fn set_mirror(mirror: FramebufferConsole) {
+    unsafe {
+        MIRROR = Some(mirror);
+    }
+}
+
+///Stops mirroring the console, handing the framebuffer console back
+pub fn release_mirror() -> Option<FramebufferConsole> {
+    unsafe { MIRROR.take() }
+}
+
+fn with_mirror<F: FnOnce(&mut FramebufferConsole)>(f: F) {
+    unsafe {
+        if let Some(ref mut mirror) = MIRROR {
+            f(mirror);
+        }
+    }
+}
+
+///Set while a with_console closure has the console, so the device can't be reached a second way
+///while it is borrowed
+static mut CONSOLE_BUSY: bool = false;
+
+fn assert_console_idle() {
+    unsafe {
+        assert!(!CONSOLE_BUSY, "console used again from inside with_console");
+    }
+}
+
+///Makes an already set up device the console, replacing the previous one.
+///The device is built by the caller so it can be configured first with its builder functions.
+///The previous console still holds its pins until this is called, so to switch between UARTs
+///that share pins call release_console before building the new one
+pub fn set_console<T: Into<Console>>(device: T) {
+    assert_console_idle();
+    unsafe {
+        CONSOLE = Some(device.into());
+    }
+}
+
+///Runs f with the console device. If none has been chosen yet the Mini Uart is set up as the
+///console, the same as the kernel has always done. The console can't be used again from inside
+///f, that panics rather than handing out a second borrow of it
+pub fn with_console<R, F: FnOnce(&mut SerialPort) -> R>(f: F) -> R {
+    assert_console_idle();
+    unsafe {
+        if CONSOLE.is_none() {
+            CONSOLE = Some(Console::MiniUart(Uart::new()));
+        }
+        CONSOLE_BUSY = true;
+        let result = match CONSOLE {
+            Some(ref mut console) => f(console),
+            None => unreachable!(),
+        };
+        CONSOLE_BUSY = false;
+        result
+    }
+}
+
+///Drops the console device, giving its pins back, until the next call to set_console or
+///with_console
+pub fn release_console() {
+    assert_console_idle();
+    unsafe {
+        CONSOLE = None;
+    }
+}
+
+///Which device is the console, if one has been chosen
+pub fn console_device() -> Option<ConsoleDevice> {
+    unsafe {
+        match CONSOLE {
+            Some(Console::MiniUart(_)) => Some(ConsoleDevice::MiniUart),
+            Some(Console::Pl011(_)) => Some(ConsoleDevice::Pl011),
+            None => None,
+        }
+    }
+}
+
+///Re-creates the console device from scratch in its plain polled mode.
+///Only for the panic handler, which can't trust the state the device was left in or rely on
+///interrupts to drain a transmit buffer. The pins are stolen rather than claimed, if they were
+///held by something else claiming them would panic again and the handler would never finish.
+///Mirroring is stopped too, the panic might have come from drawing on the screen. The panic may
+///have happened inside with_console, which is never going to finish now, so its borrow is
+///dropped as well
+pub fn reset_console() {
+    unsafe {
+        CONSOLE_BUSY = false;
+    }
+    let device = console_device();
+    release_mirror();
+    release_console();
+    match device {
+        Some(ConsoleDevice::Pl011) => set_console(Pl011::steal()),
+        _ => set_console(Uart::steal()),
+    }
+}
@@ -0,0 +1,385 @@
+/// Software PWM for dimming LEDs on any output pin, and effects that change the brightness over
+/// time such as fades, breathing and blinking.
+///
+/// Each LED is switched at 100Hz, with its on time set to the microsecond. Rather than a fixed
+/// tick the engine only takes an interrupt when a pin has to change: a periodic timeout on the
+/// timer_wheel starts every 10ms period by stepping the effects and switching on every LED that
+/// isn't off, then a one shot timeout is scheduled for the next moment one of them has to go off.
+/// That is at most one interrupt per attached LED plus one each period, however fine the
+/// brightness steps are. The engine holds at most two of the wheel's slots. When every LED is
+/// fully on or off and no effect is running both timeouts are cancelled, so the core can sleep.
+///
+/// Effects are just data, a list of steps and how often to repeat them, so new ones can be
+/// described without writing any code:
+///
+/// static BLINK: Effect = Effect { name: "blink", steps: &[Step::Set(100), Step::Hold(500),
+///     Step::Set(0), Step::Hold(500)], repeat: Repeat::Forever };
+
+use arch::without_interrupts;
+use gpio::{Gpio, Output};
+use timer::{current_time_ms, SystemTimer};
+use timer_wheel::{self, TimerHandle};
+
+///Length of each PWM period in microseconds, 100Hz is too fast to see flicker
+const PERIOD_MICROS: u64 = 10_000;
+
+///How many LEDs can be attached at once
+const SLOTS: usize = 8;
+
+///Brightness when fully on
+pub const FULL: u8 = 100;
+
+///One step of an effect
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    ///Jump straight to a brightness
+    Set(u8),
+    ///Stay at the current brightness for so many milliseconds
+    Hold(u32),
+    ///Change smoothly from the current brightness to another over so many milliseconds
+    Fade { to: u8, millis: u32 },
+}
+
+///How many times an effect runs through its steps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Repeat {
+    Once,
+    Times(u32),
+    Forever,
+}
+
+///A named sequence of steps
+#[derive(Debug)]
+pub struct Effect {
+    pub name: &'static str,
+    pub steps: &'static [Step],
+    pub repeat: Repeat,
+}
+
+///The effects the shell can start by name
+pub static EFFECTS: [Effect; 5] = [
+    Effect {
+        name: "blink",
+        steps: &[Step::Set(FULL), Step::Hold(500), Step::Set(0), Step::Hold(500)],
+        repeat: Repeat::Forever,
+    },
+    Effect {
+        name: "breathe",
+        steps: &[
+            Step::Fade { to: FULL, millis: 1500 },
+            Step::Fade { to: 0, millis: 1500 },
+            Step::Hold(500),
+        ],
+        repeat: Repeat::Forever,
+    },
+    Effect {
+        name: "fadein",
+        steps: &[Step::Fade { to: FULL, millis: 1000 }],
+        repeat: Repeat::Once,
+    },
+    Effect {
+        name: "fadeout",
+        steps: &[Step::Fade { to: 0, millis: 1000 }],
+        repeat: Repeat::Once,
+    },
+    Effect {
+        name: "heartbeat",
+        steps: &[
+            Step::Set(FULL),
+            Step::Fade { to: 0, millis: 150 },
+            Step::Set(FULL),
+            Step::Fade { to: 0, millis: 400 },
+            Step::Hold(600),
+        ],
+        repeat: Repeat::Forever,
+    },
+];
+
+///Looks up one of the built in effects by name
+pub fn find_effect(name: &str) -> Option<&'static Effect> {
+    EFFECTS.iter().find(|effect| effect.name == name)
+}
+
+///Handle to an attached LED
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LedId(usize);
+
+///Where an LED is up to in its effect
+#[derive(Clone, Copy)]
+struct Running {
+    effect: &'static Effect,
+    step: usize,
+    ///When the current step started, in milliseconds
+    started: u64,
+    ///Brightness when the current step started, where a fade starts from
+    from: u8,
+    ///How many times the steps have been run through
+    passes: u32,
+}
+
+struct Led {
+    gpio: Gpio<Output>,
+    level: u8,
+    ///If the pin is high right now, so it is only written when it changes
+    lit: bool,
+    running: Option<Running>,
+}
+
+impl Led {
+    ///Moves the effect on to the current time, clearing it once it has finished
+    fn advance(&mut self, now: u64) {
+        let mut running = match self.running {
+            Some(running) => running,
+            None => return,
+        };
+        //Bounded so an effect made only of Set steps can't loop forever in one go
+        for _ in 0..running.effect.steps.len() + 1 {
+            let elapsed = now.saturating_sub(running.started);
+            let duration = match running.effect.steps[running.step] {
+                Step::Set(level) => {
+                    self.level = level.min(FULL);
+                    0
+                }
+                Step::Hold(millis) => millis as u64,
+                Step::Fade { to, millis } => {
+                    let to = to.min(FULL);
+                    if elapsed < millis as u64 {
+                        let from = running.from as i64;
+                        let change = (to as i64 - from) * elapsed as i64 / millis as i64;
+                        self.level = (from + change) as u8;
+                    } else {
+                        self.level = to;
+                    }
+                    millis as u64
+                }
+            };
+            if elapsed < duration {
+                break;
+            }
+
+            //Step finished, start the next from when this one was due to end so the timing
+            //doesn't drift
+            running.started += duration;
+            running.from = self.level;
+            running.step += 1;
+            if running.step == running.effect.steps.len() {
+                running.step = 0;
+                running.passes += 1;
+                let finished = match running.effect.repeat {
+                    Repeat::Once => true,
+                    Repeat::Times(times) => running.passes >= times,
+                    Repeat::Forever => false,
+                };
+                if finished {
+                    self.running = None;
+                    return;
+                }
+            }
+        }
+        self.running = Some(running);
+    }
+
+    ///Switches the pin on or off, only writing it if that changes it
+    fn switch(&mut self, on: bool) {
+        if on != self.lit {
+            match on {
+                true => self.gpio.set(),
+                false => self.gpio.clear(),
+            }
+            self.lit = on;
+        }
+    }
+
+    ///If the LED needs the timer, to run an effect or to be dimmed
+    fn needs_timer(&self) -> bool {
+        self.running.is_some() || (self.level != 0 && self.level != FULL)
+    }
+}
+
+///How many microseconds of each period the LED is on for. Eyes are much more sensitive to changes
+///at low brightness, squaring the level makes fades look even instead of jumping up at the start
+fn on_micros(level: u8) -> u64 {
+    let (level, full) = (level as u64, FULL as u64);
+    (level * level * PERIOD_MICROS + full * full - 1) / (full * full)
+}
+
+///Statically allocated storage for the attached LEDs, shared with the timer interrupts
+static mut LEDS: [Option<Led>; SLOTS] = [None, None, None, None, None, None, None, None];
+
+///The periodic timeout that starts each PWM period, while any LED needs one
+static mut PERIOD: Option<TimerHandle> = None;
+
+///The one shot timeout for the next LED to switch off this period
+static mut EDGE: Option<TimerHandle> = None;
+
+///When the current period started on the system timer, which the wheel runs on
+static mut PERIOD_START: u64 = 0;
+
+///Runs from the wheel at the start of every period. Steps the effects, switches on every LED
+///that isn't off and schedules the first switch off
+fn start_period() {
+    unsafe {
+        PERIOD_START = SystemTimer::new().read();
+        let now = current_time_ms();
+        for slot in LEDS.iter_mut() {
+            if let Some(ref mut led) = *slot {
+                led.advance(now);
+                let on = led.level != 0;
+                led.switch(on);
+            }
+        }
+    }
+    schedule_edge();
+    //An effect may have finished and left nothing that needs dimming
+    update_timer();
+}
+
+///Runs from the wheel when an LED's on time is up, switching off every LED that is due
+fn end_on_time() {
+    unsafe {
+        EDGE = None;
+        let elapsed = SystemTimer::new().read().saturating_sub(PERIOD_START);
+        for slot in LEDS.iter_mut() {
+            if let Some(ref mut led) = *slot {
+                if led.lit && on_micros(led.level) <= elapsed {
+                    led.switch(false);
+                }
+            }
+        }
+    }
+    schedule_edge();
+}
+
+///Schedules end_on_time for the next lit LED that has to go off before the period ends. If the
+///wheel is full the LEDs stay on for the rest of this period and dimming is tried again next one
+fn schedule_edge() {
+    unsafe {
+        let next = LEDS
+            .iter()
+            .filter_map(|slot| slot.as_ref())
+            .filter(|led| led.lit)
+            .map(|led| on_micros(led.level))
+            .filter(|&on| on < PERIOD_MICROS)
+            .min();
+        if let Some(on) = next {
+            let now = SystemTimer::new().read();
+            let delay = (PERIOD_START + on).saturating_sub(now);
+            EDGE = timer_wheel::schedule_after(delay, end_on_time).ok();
+        }
+    }
+}
+
+///Starts the periodic timeout if any LED needs it and stops it if none do. When stopped every
+///pin is left fully on or off. If the wheel is full nothing is dimmed until the next call finds
+///room for it
+fn update_timer() {
+    without_interrupts(|| unsafe {
+        let needed = LEDS
+            .iter()
+            .any(|slot| slot.as_ref().map_or(false, |led| led.needs_timer()));
+        match PERIOD {
+            None if needed => {
+                PERIOD = timer_wheel::schedule_every(PERIOD_MICROS, start_period).ok();
+            }
+            Some(period) if !needed => {
+                timer_wheel::cancel(period);
+                PERIOD = None;
+                if let Some(edge) = EDGE.take() {
+                    timer_wheel::cancel(edge);
+                }
+                for slot in LEDS.iter_mut() {
+                    if let Some(ref mut led) = *slot {
+                        let on = led.level != 0;
+                        led.switch(on);
+                    }
+                }
+            }
+            _ => {}
+        }
+    })
+}
+
+///Runs a function on an attached LED with the timer interrupts held off, then starts or stops
+///the PWM timeouts to suit. Panics on a LedId that has been detached
+fn with_led<F: FnOnce(&mut Led) -> R, R>(id: LedId, f: F) -> R {
+    let out = without_interrupts(|| unsafe {
+        match LEDS[id.0] {
+            Some(ref mut led) => f(led),
+            None => panic!("LED {} is not attached", id.0),
+        }
+    });
+    update_timer();
+    out
+}
+
+///Hands an output pin over to the engine, starting off. Fails if all the slots are in use, the
+///pin is dropped and given back to the registry in that case
+pub fn attach(mut gpio: Gpio<Output>) -> Result<LedId, ()> {
+    gpio.clear();
+    without_interrupts(|| unsafe {
+        match LEDS.iter().position(|slot| slot.is_none()) {
+            Some(i) => {
+                LEDS[i] = Some(Led {
+                    gpio,
+                    level: 0,
+                    lit: false,
+                    running: None,
+                });
+                Ok(LedId(i))
+            }
+            None => Err(()),
+        }
+    })
+}
+
+///Takes the pin back out of the engine, as it was left
+pub fn detach(led: LedId) -> Option<Gpio<Output>> {
+    let out = without_interrupts(|| unsafe { LEDS[led.0].take().map(|led| led.gpio) });
+    update_timer();
+    out
+}
+
+///Sets the brightness from 0 (off) to 100 (fully on), stopping any effect
+pub fn set_brightness(led: LedId, level: u8) {
+    with_led(led, |led| {
+        led.running = None;
+        led.level = level.min(FULL);
+        //Full on and off won't get the timer started, so switch the pin now
+        if led.level == 0 || led.level == FULL {
+            let on = led.level != 0;
+            led.switch(on);
+        }
+    })
+}
+
+pub fn brightness(led: LedId) -> u8 {
+    with_led(led, |led| led.level)
+}
+
+///Starts an effect from its first step, from the LED's current brightness
+pub fn start(led: LedId, effect: &'static Effect) {
+    if effect.steps.is_empty() {
+        return;
+    }
+    let now = current_time_ms();
+    with_led(led, |led| {
+        led.running = Some(Running {
+            effect,
+            step: 0,
+            started: now,
+            from: led.level,
+            passes: 0,
+        });
+        led.advance(now);
+    })
+}
+
+///Stops the effect, leaving the LED at whatever brightness it had got to
+pub fn stop(led: LedId) {
+    with_led(led, |led| led.running = None)
+}
+
+///The effect running on the LED, if any
+pub fn running(led: LedId) -> Option<&'static Effect> {
+    with_led(led, |led| led.running.map(|running| running.effect))
+}
@@ -0,0 +1,450 @@
+/// Documentation for the SPI0 master can be found on page 148 of the Broadcom manual.
+///
+/// SPI is full duplex: every byte clocked out on MOSI clocks one back in on MISO at the same
+/// time, so a transfer swaps the contents of a buffer with the device. To only write, ignore
+/// what comes back, to only read, send zeros.
+///
+/// There is also SoftSpi, which bit-bangs the same thing on any 3 or 4 pins for when the
+/// hardware pins are taken. Drivers for SPI devices should take a SpiBus so they work with either.
+
+use common::SPI0_BASE;
+use core::fmt;
+use core::ptr;
+use dma::{self, ControlBlock, Dreq};
+use gpio::{Alt, AltFunction, Gpio, Input, Output, PinError};
+use mailbox::{self, ClockId};
+use timer::{current_time, spin_sleep_micros};
+use volatile::Volatile;
+
+///Register map from page 152 of the Broadcom manual
+///
+///This structure uses [repr(C)].
+///This means that the compiler is NOT free to reorder the fields of this structure for alignment
+///reasons, its very important the layout is exactly as ive defined it.
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    ///Control and status
+    CS: Volatile<u32>,
+    ///TX and RX FIFOs, 16 words deep
+    FIFO: Volatile<u32>,
+    ///Clock divider
+    CLK: Volatile<u32>,
+    ///Data length, only used in DMA mode
+    DLEN: Volatile<u32>,
+    ///LoSSI output hold delay
+    LTOH: Volatile<u32>,
+    ///DMA DREQ thresholds
+    DC: Volatile<u32>,
+}
+
+///CS bits
+const CS_CPHA: u32 = 1 << 2;
+const CS_CPOL: u32 = 1 << 3;
+const CS_CLEAR: u32 = 0b11 << 4;
+const CS_TA: u32 = 1 << 7;
+const CS_DMAEN: u32 = 1 << 8;
+const CS_ADCS: u32 = 1 << 11;
+const CS_DONE: u32 = 1 << 16;
+const CS_RXD: u32 = 1 << 17;
+const CS_TXD: u32 = 1 << 18;
+
+///Core clock to assume if the mailbox can't tell us, SPI0 is clocked from it
+const DEFAULT_CORE_CLOCK: u32 = 250_000_000;
+
+///Longest transfer transfer_dma can do, limited by the static buffers
+pub const DMA_MAX: usize = 4096;
+
+///Extra time allowed on top of how long a DMA transfer should take, in microseconds
+const TIMEOUT_SLACK: u64 = 10_000;
+
+///The four ways a device can expect the clock, from the clock polarity (CPOL, the level SCLK
+///idles at) and clock phase (CPHA, whether data is sampled on the first or second edge)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    ///CPOL 0 CPHA 0, the most common
+    Mode0,
+    ///CPOL 0 CPHA 1
+    Mode1,
+    ///CPOL 1 CPHA 0
+    Mode2,
+    ///CPOL 1 CPHA 1
+    Mode3,
+}
+
+impl Mode {
+    fn cpol(&self) -> bool {
+        *self == Mode::Mode2 || *self == Mode::Mode3
+    }
+    fn cpha(&self) -> bool {
+        *self == Mode::Mode1 || *self == Mode::Mode3
+    }
+}
+
+///Which chip select line is pulled low during a transfer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChipSelect {
+    ///GPIO8
+    Ce0 = 0,
+    ///GPIO7
+    Ce1 = 1,
+    ///Neither, for devices with their chip select on another Gpio driven by the caller
+    None = 3,
+}
+
+///Reasons a DMA transfer can fail
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpiError {
+    ///with_dma hasn't been called
+    NoDma,
+    ///More than DMA_MAX bytes
+    TooLong,
+    ///The DMA channels didn't finish in time
+    Timeout,
+    ///A DMA channel stopped with a bus error
+    Dma,
+}
+
+impl fmt::Display for SpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            SpiError::NoDma => "no DMA channels set up",
+            SpiError::TooLong => "transfer too long for DMA",
+            SpiError::Timeout => "DMA transfer timed out",
+            SpiError::Dma => "DMA bus error",
+        };
+        f.write_str(s)
+    }
+}
+
+///Anything that can do a full duplex SPI transfer, implemented by Spi and SoftSpi
+pub trait SpiBus {
+    ///Sends every byte in the buffer, replacing each with the byte received at the same time
+    fn transfer(&mut self, buffer: &mut [u8]);
+}
+
+///Statically allocated storage for DMA transfers, the DMA engine reads and writes these directly.
+///TX starts with the word that sets DLEN and CS
+#[repr(C, align(32))]
+struct DmaBuffers {
+    tx: [u8; DMA_MAX + 4],
+    rx: [u8; DMA_MAX + 4],
+    tx_block: ControlBlock,
+    rx_block: ControlBlock,
+}
+
+static mut DMA_BUFFERS: DmaBuffers = DmaBuffers {
+    tx: [0; DMA_MAX + 4],
+    rx: [0; DMA_MAX + 4],
+    tx_block: ControlBlock::new(),
+    rx_block: ControlBlock::new(),
+};
+
+///Wrapper for the SPI0 registers, on GPIO7 to 11
+pub struct Spi {
+    registers: &'static mut Registers,
+    ///Held so nothing else can take them while the device exists
+    _pins: [Gpio<Alt>; 5],
+    core_clock: u32,
+    ///SCLK frequency in Hz
+    clock: u32,
+    ///Mode and chip select bits for CS
+    cs: u32,
+    ///TX and RX channels for transfer_dma
+    dma: Option<(dma::Channel, dma::Channel)>,
+}
+
+impl Spi {
+    ///Constructor, claims GPIO7 to 11 and sets up mode 0 on CE0 at 1MHz
+    pub fn new() -> Result<Spi, PinError> {
+        //CE1, CE0, MISO, MOSI and SCLK, all AltFunction 0, see page 102 of the Broadcom manual
+        let ce1 = Gpio::claim(7, "spi0")?;
+        let ce0 = Gpio::claim(8, "spi0")?;
+        let miso = Gpio::claim(9, "spi0")?;
+        let mosi = Gpio::claim(10, "spi0")?;
+        let sclk = Gpio::claim(11, "spi0")?;
+        let core_clock = mailbox::clock_rate(ClockId::Core).unwrap_or(DEFAULT_CORE_CLOCK);
+        let mut spi = Spi {
+            registers: unsafe { &mut *(SPI0_BASE as *mut Registers) },
+            _pins: [
+                ce1.as_alt(AltFunction(0)),
+                ce0.as_alt(AltFunction(0)),
+                miso.as_alt(AltFunction(0)),
+                mosi.as_alt(AltFunction(0)),
+                sclk.as_alt(AltFunction(0)),
+            ],
+            core_clock,
+            clock: 0,
+            cs: ChipSelect::Ce0 as u32,
+            dma: None,
+        };
+        spi.registers.CS.write(CS_CLEAR);
+        spi.set_clock(1_000_000);
+        Ok(spi)
+    }
+
+    ///Set the mode via builder pattern
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.set_mode(mode);
+        self
+    }
+
+    ///Set the SCLK frequency via builder pattern
+    pub fn with_clock(mut self, hz: u32) -> Self {
+        self.set_clock(hz);
+        self
+    }
+
+    ///Set the chip select line via builder pattern
+    pub fn with_chip_select(mut self, cs: ChipSelect) -> Self {
+        self.set_chip_select(cs);
+        self
+    }
+
+    ///Use two DMA channels for transfer_dma via builder pattern, see dma.rs for which are free
+    pub fn with_dma(mut self, tx_channel: u8, rx_channel: u8) -> Self {
+        self.dma = Some((dma::Channel::new(tx_channel), dma::Channel::new(rx_channel)));
+        self
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.cs &= !(CS_CPOL | CS_CPHA);
+        if mode.cpol() {
+            self.cs |= CS_CPOL;
+        }
+        if mode.cpha() {
+            self.cs |= CS_CPHA;
+        }
+        //Let SCLK settle at its new idle level before the next transfer pulls CS low
+        self.registers.CS.write(self.cs);
+    }
+
+    pub fn set_chip_select(&mut self, cs: ChipSelect) {
+        self.cs = (self.cs & !0b11) | cs as u32;
+    }
+
+    ///Sets the SCLK frequency, returning the frequency actually achieved. The divider is
+    ///rounded up to an even number so this never goes faster than asked
+    pub fn set_clock(&mut self, hz: u32) -> u32 {
+        let hz = hz.max(1);
+        let mut divider = (self.core_clock + hz - 1) / hz;
+        divider += divider & 1;
+        let divider = divider.max(2).min(0xFFFE);
+        self.registers.CLK.write(divider);
+        self.clock = self.core_clock / divider;
+        self.clock
+    }
+
+    ///SCLK frequency in Hz
+    pub fn clock(&self) -> u32 {
+        self.clock
+    }
+
+    ///Like transfer but the data is moved by two DMA channels instead of the CPU, for long
+    ///transfers such as filling a display. The CPU still waits for it to finish
+    pub fn transfer_dma(&mut self, buffer: &mut [u8]) -> Result<(), SpiError> {
+        if buffer.len() > DMA_MAX {
+            return Err(SpiError::TooLong);
+        }
+        let (tx, rx) = match self.dma {
+            Some((ref mut tx, ref mut rx)) => (tx, rx),
+            None => return Err(SpiError::NoDma),
+        };
+        let len = buffer.len();
+        let fifo = dma::bus_io_address(SPI0_BASE + 4);
+
+        unsafe {
+            let buffers = &mut DMA_BUFFERS;
+            //In DMA mode the first word written to the FIFO sets DLEN and the bottom byte of CS,
+            //which starts the transfer, page 158
+            let header = (len as u32) << 16 | (self.cs & 0xFF) | CS_TA;
+            ptr::write_volatile(buffers.tx.as_mut_ptr() as *mut u32, header);
+            for (i, b) in buffer.iter().enumerate() {
+                ptr::write_volatile(&mut buffers.tx[4 + i], *b);
+            }
+
+            //The FIFO is read and written a word at a time, round up to whole words
+            let words = (len as u32 + 3) & !3;
+            buffers.rx_block = ControlBlock::new();
+            buffers.rx_block.ti =
+                dma::TI_SRC_DREQ | dma::TI_DEST_INC | dma::TI_WAIT_RESP | Dreq::SpiRx.permap();
+            buffers.rx_block.source = fifo;
+            buffers.rx_block.dest = dma::bus_address(buffers.rx.as_ptr());
+            buffers.rx_block.length = words;
+            buffers.tx_block = ControlBlock::new();
+            buffers.tx_block.ti =
+                dma::TI_DEST_DREQ | dma::TI_SRC_INC | dma::TI_WAIT_RESP | Dreq::SpiTx.permap();
+            buffers.tx_block.source = dma::bus_address(buffers.tx.as_ptr());
+            buffers.tx_block.dest = fifo;
+            buffers.tx_block.length = 4 + words;
+
+            self.registers.CS.write(self.cs | CS_CLEAR | CS_DMAEN | CS_ADCS);
+            //Receive first so it is ready to catch the first byte
+            rx.start(&buffers.rx_block);
+            tx.start(&buffers.tx_block);
+        }
+
+        let bits = len as u64 * 8;
+        let deadline = current_time() + bits * 1_000_000 / self.clock as u64 + TIMEOUT_SLACK;
+        let mut result = Ok(());
+        while !(tx.is_done() && rx.is_done()) {
+            if tx.has_error() || rx.has_error() {
+                result = Err(SpiError::Dma);
+                break;
+            }
+            if current_time() > deadline {
+                result = Err(SpiError::Timeout);
+                break;
+            }
+        }
+        if result.is_err() {
+            tx.abort();
+            rx.abort();
+        }
+        self.registers.CS.write(self.cs | CS_CLEAR);
+
+        if result.is_ok() {
+            unsafe {
+                for (i, b) in buffer.iter_mut().enumerate() {
+                    *b = ptr::read_volatile(&DMA_BUFFERS.rx[i]);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl SpiBus for Spi {
+    ///Polled transfer, keeping the TX FIFO topped up and the RX FIFO emptied until every byte
+    ///has gone both ways
+    fn transfer(&mut self, buffer: &mut [u8]) {
+        self.registers.CS.write(self.cs | CS_CLEAR | CS_TA);
+        let (mut sent, mut received) = (0, 0);
+        while received < buffer.len() {
+            //Bytes are only written back over ones that have already been sent
+            while sent < buffer.len() && self.registers.CS.read() & CS_TXD != 0 {
+                self.registers.FIFO.write(buffer[sent] as u32);
+                sent += 1;
+            }
+            while received < sent && self.registers.CS.read() & CS_RXD != 0 {
+                buffer[received] = self.registers.FIFO.read() as u8;
+                received += 1;
+            }
+        }
+        while self.registers.CS.read() & CS_DONE == 0 {}
+        self.registers.CS.write(self.cs);
+    }
+}
+
+///SPI bit-banged on any pins. Only as fast as the Gpio writes and delays allow, a few hundred
+///kHz, but enough for ADCs and small displays
+pub struct SoftSpi {
+    sclk: Gpio<Output>,
+    mosi: Gpio<Output>,
+    miso: Gpio<Input>,
+    cs: Option<Gpio<Output>>,
+    mode: Mode,
+    ///Microseconds between clock edges, 0 runs as fast as the pins can go
+    half_period: u64,
+}
+
+impl SoftSpi {
+    ///Constructor, mode 0 at about 100kHz. With a chip select pin it is held high (inactive)
+    ///between transfers and pulled low for each
+    pub fn new(
+        sclk: Gpio<Output>,
+        mosi: Gpio<Output>,
+        miso: Gpio<Input>,
+        cs: Option<Gpio<Output>>,
+    ) -> SoftSpi {
+        let mut spi = SoftSpi {
+            sclk,
+            mosi,
+            miso,
+            cs,
+            mode: Mode::Mode0,
+            half_period: 5,
+        };
+        if let Some(ref mut cs) = spi.cs {
+            cs.set();
+        }
+        spi.sclk.clear();
+        spi
+    }
+
+    ///Set the mode via builder pattern
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self.set_sclk(false);
+        self
+    }
+
+    ///Set the SCLK frequency via builder pattern, rounded down to whole microsecond delays
+    pub fn with_clock(mut self, hz: u32) -> Self {
+        self.half_period = 500_000 / hz.max(1) as u64;
+        self
+    }
+
+    ///Drives SCLK to its active level or back to idle, which depends on CPOL
+    fn set_sclk(&mut self, active: bool) {
+        match active != self.mode.cpol() {
+            true => self.sclk.set(),
+            false => self.sclk.clear(),
+        }
+    }
+
+    fn set_mosi(&mut self, bit: bool) {
+        match bit {
+            true => self.mosi.set(),
+            false => self.mosi.clear(),
+        }
+    }
+
+    fn delay(&self) {
+        if self.half_period != 0 {
+            spin_sleep_micros(self.half_period);
+        }
+    }
+
+    ///Clocks one byte out and one in, most significant bit first
+    fn transfer_byte(&mut self, out: u8) -> u8 {
+        let mut input = 0;
+        for bit in (0..8).rev() {
+            let out_bit = out & (1 << bit) != 0;
+            if self.mode.cpha() {
+                //Data changes on the leading edge and is sampled on the trailing one
+                self.set_sclk(true);
+                self.set_mosi(out_bit);
+                self.delay();
+                self.set_sclk(false);
+                input |= (self.miso.read_level() as u8) << bit;
+                self.delay();
+            } else {
+                //Data is set up before the leading edge and sampled on it
+                self.set_mosi(out_bit);
+                self.delay();
+                self.set_sclk(true);
+                input |= (self.miso.read_level() as u8) << bit;
+                self.delay();
+                self.set_sclk(false);
+            }
+        }
+        input
+    }
+}
+
+impl SpiBus for SoftSpi {
+    fn transfer(&mut self, buffer: &mut [u8]) {
+        if let Some(ref mut cs) = self.cs {
+            cs.clear();
+        }
+        self.delay();
+        for b in buffer.iter_mut() {
+            *b = self.transfer_byte(*b);
+        }
+        self.delay();
+        if let Some(ref mut cs) = self.cs {
+            cs.set();
+        }
+    }
+}
@@ -1,6 +1,8 @@
 /// Documentation for the hardware for the timer can be found on page 172 of the Broadcom manual
 
+use arch::{core_id, without_interrupts};
 use common::TIMER_BASE;
+use interrupt::{self, Interrupt, LocalInterrupt};
 ///Use the volatile wrappers ReadOnly and Volatile
 ///They're functionaly identical and really don't do much, they just prevent accidental
 ///non-volatile reads/writes
@@ -55,11 +57,341 @@ impl SystemTimer {
         //Bitshift the higher bits to the left 32 and use binary OR to combine them
         (higher << 32) | lower
     }
+
+    ///Sets the value of CLO that will trigger a match on this compare channel
+    pub fn set_compare(&mut self, channel: Channel, value: u32) {
+        self.registers.COMPARE[channel as usize].write(value);
+    }
+
+    ///Gets the value of CLO that will trigger a match on this compare channel
+    pub fn compare(&self, channel: Channel) -> u32 {
+        self.registers.COMPARE[channel as usize].read()
+    }
+
+    ///Checks the match bit for this channel in CS, set when CLO reaches the compare value
+    pub fn matched(&self, channel: Channel) -> bool {
+        self.registers.CS.read() & (1 << channel as u32) != 0
+    }
+
+    ///Clears the match bit for this channel, which also deasserts its interrupt.
+    ///Bits in CS are cleared by writing a 1 to them, writing 0 leaves the other channels alone
+    pub fn clear_match(&mut self, channel: Channel) {
+        self.registers.CS.write(1 << channel as u32);
+    }
+}
+
+///The compare channels the ARM is free to use, channels 0 and 2 are used by the GPU
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    One = 1,
+    Three = 3,
+}
+
+impl Channel {
+    ///The interrupt controller source for this channel
+    fn interrupt(self) -> Interrupt {
+        match self {
+            Channel::One => Interrupt::Timer1,
+            Channel::Three => Interrupt::Timer3,
+        }
+    }
+}
+
+///Whether an armed channel fires once or keeps firing every interval
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    OneShot,
+    Periodic,
+}
+
+///What an armed compare channel or generic timer should do when it fires
+#[derive(Clone, Copy)]
+struct CompareState {
+    interval: u32,
+    mode: Mode,
+    callback: fn(),
+}
+
+///Statically allocated state for each compare channel, indexed by channel number
+static mut COMPARE_STATE: [Option<CompareState>; 4] = [None; 4];
+
+///Arms a compare channel to call the callback from IRQ context in so many microseconds,
+///and then every so many microseconds after that if the mode is Periodic.
+///Re-arming a channel that is already running replaces its callback and interval
+pub fn arm(channel: Channel, micros: u32, mode: Mode, callback: fn()) {
+    without_interrupts(|| {
+        unsafe {
+            COMPARE_STATE[channel as usize] = Some(CompareState {
+                interval: micros,
+                mode,
+                callback,
+            });
+        }
+        let mut timer = SystemTimer::new();
+        timer.clear_match(channel);
+        //Only the lower 32 bits are compared so this wraps around with CLO
+        let now = timer.registers.CLO.read();
+        timer.set_compare(channel, now.wrapping_add(micros));
+        interrupt::register(channel.interrupt(), channel_irq(channel));
+    })
+}
+
+///Stops a compare channel firing and forgets its callback
+pub fn disarm(channel: Channel) {
+    without_interrupts(|| {
+        interrupt::unregister(channel.interrupt());
+        SystemTimer::new().clear_match(channel);
+        unsafe {
+            COMPARE_STATE[channel as usize] = None;
+        }
+    })
+}
+
+///Checks if a compare channel is currently armed
+pub fn is_armed(channel: Channel) -> bool {
+    unsafe { COMPARE_STATE[channel as usize].is_some() }
+}
+
+///Interrupt handlers can't take arguments, so each channel gets its own small function
+fn channel_irq(channel: Channel) -> fn() {
+    match channel {
+        Channel::One => timer1_irq,
+        Channel::Three => timer3_irq,
+    }
+}
+
+fn timer1_irq() {
+    handle_compare(Channel::One)
+}
+
+fn timer3_irq() {
+    handle_compare(Channel::Three)
+}
+
+///Acknowledges the match, re-arms or disarms the channel and then runs its callback
+fn handle_compare(channel: Channel) {
+    let mut timer = SystemTimer::new();
+    timer.clear_match(channel);
+    let state = match unsafe { COMPARE_STATE[channel as usize] } {
+        Some(state) => state,
+        None => return,
+    };
+    match state.mode {
+        Mode::Periodic => {
+            //Step from the last compare value rather than from now so the period doesn't drift,
+            //unless we are so late that the next match has already gone past
+            let now = timer.registers.CLO.read();
+            let mut next = timer.compare(channel).wrapping_add(state.interval);
+            if (next.wrapping_sub(now) as i32) <= 0 {
+                next = now.wrapping_add(state.interval);
+            }
+            timer.set_compare(channel, next);
+        }
+        Mode::OneShot => {
+            interrupt::unregister(channel.interrupt());
+            unsafe {
+                COMPARE_STATE[channel as usize] = None;
+            }
+        }
+    }
+    (state.callback)();
+}
+
+///A free running counter that counts up in microseconds, implemented by every timer backend so
+///the sleep functions below don't care which one they're using
+pub trait Counter {
+    ///Reads elapsed time in microseconds
+    fn read(&self) -> u64;
+
+    ///Locks the thread for so many microseconds on this clock
+    fn spin_sleep_micros(&self, micros: u64) {
+        let start = self.read();
+        while self.read() < start + micros {}
+    }
+}
+
+impl Counter for SystemTimer {
+    fn read(&self) -> u64 {
+        SystemTimer::read(self)
+    }
+}
+
+///The ARMv8 generic timer, documented in chapter D10 of the ARMv8 Architecture Reference Manual.
+///
+///Unlike the system timer there is one of these per core and it is accessed through system
+///registers rather than MMIO, so there are no registers to map. The boot code in ext/crt0.S
+///sets CNTHCTL_EL2 so EL1 is allowed to use the physical counter and timer.
+pub struct GenericTimer;
+
+impl GenericTimer {
+    ///Constructor, there is nothing to set up as the counter is always running
+    pub fn new() -> GenericTimer {
+        GenericTimer
+    }
+
+    ///Frequency of the counter in Hz from CNTFRQ_EL0, set by the firmware (19.2MHz on the Pi 3)
+    pub fn frequency(&self) -> u64 {
+        let freq: u64;
+        unsafe {
+            asm!("mrs $0, cntfrq_el0" : "=r"(freq) ::: "volatile");
+        }
+        freq
+    }
+
+    ///Reads the raw physical count from CNTPCT_EL0.
+    ///The isb stops the read being done early, out of order with the code before it
+    pub fn counter(&self) -> u64 {
+        let count: u64;
+        unsafe {
+            asm!("isb
+                  mrs $0, cntpct_el0" : "=r"(count) ::: "volatile");
+        }
+        count
+    }
+
+    ///Starts the timer so it asserts its interrupt in so many microseconds.
+    ///CNTP_TVAL_EL0 is a signed 32 bit down counter so the delay is capped at i32::MAX ticks,
+    ///about 111 seconds at 19.2MHz. The multiply saturates so a huge delay is capped the same way
+    ///instead of overflowing into a short one
+    pub fn arm(&mut self, micros: u64) {
+        let ticks = micros.saturating_mul(self.frequency()) / 1_000_000;
+        let ticks = if ticks > i32::max_value() as u64 {
+            i32::max_value() as u64
+        } else {
+            ticks
+        };
+        unsafe {
+            asm!("msr cntp_tval_el0, $0" :: "r"(ticks) :: "volatile");
+            //ENABLE set and IMASK clear
+            asm!("msr cntp_ctl_el0, $0" :: "r"(1u64) :: "volatile");
+        }
+    }
+
+    ///Stops the timer, which also deasserts its interrupt
+    pub fn disarm(&mut self) {
+        unsafe {
+            asm!("msr cntp_ctl_el0, $0" :: "r"(0u64) :: "volatile");
+        }
+    }
+
+    ///Checks the ISTATUS bit of CNTP_CTL_EL0, set when the timer has expired while enabled
+    pub fn is_pending(&self) -> bool {
+        let ctl: u64;
+        unsafe {
+            asm!("mrs $0, cntp_ctl_el0" : "=r"(ctl) ::: "volatile");
+        }
+        ctl & (1 << 2) != 0
+    }
+}
+
+impl Counter for GenericTimer {
+    fn read(&self) -> u64 {
+        let count = self.counter();
+        let freq = self.frequency();
+        //Split the conversion so count * 1_000_000 can't overflow after a few days of uptime
+        (count / freq) * 1_000_000 + (count % freq) * 1_000_000 / freq
+    }
+}
+
+///What each core's generic timer should do when it fires, indexed by core
+static mut LOCAL_STATE: [Option<CompareState>; 4] = [None; 4];
+
+///Arms this core's generic timer to call the callback from IRQ context in so many microseconds,
+///and then every so many microseconds after that if the mode is Periodic. The interrupt only goes
+///to the core that armed it, through the QA7 local interrupt routing, so every core can have its
+///own tick (for a scheduler say) without sharing the system timer's compare channels.
+///Re-arming replaces the callback and interval
+pub fn arm_local(micros: u32, mode: Mode, callback: fn()) {
+    let core = core_id();
+    without_interrupts(|| {
+        unsafe {
+            LOCAL_STATE[core] = Some(CompareState {
+                interval: micros,
+                mode,
+                callback,
+            });
+        }
+        GenericTimer::new().arm(micros as u64);
+        interrupt::register_local(LocalInterrupt::PhysicalTimer, local_timer_irq);
+    })
+}
+
+///Stops this core's generic timer firing and forgets its callback
+pub fn disarm_local() {
+    let core = core_id();
+    without_interrupts(|| {
+        GenericTimer::new().disarm();
+        interrupt::unregister_local(LocalInterrupt::PhysicalTimer);
+        unsafe {
+            LOCAL_STATE[core] = None;
+        }
+    })
+}
+
+///Checks if this core's generic timer is currently armed
+pub fn is_armed_local() -> bool {
+    unsafe { LOCAL_STATE[core_id()].is_some() }
+}
+
+///Interrupt handler for the generic timer of whichever core it fired on. The timer can't be
+///acknowledged, it keeps its interrupt asserted until it is re-armed or stopped. A periodic tick
+///is re-armed from now, so it drifts by however long the interrupt took to be taken
+fn local_timer_irq() {
+    let core = core_id();
+    let mut timer = GenericTimer::new();
+    let state = match unsafe { LOCAL_STATE[core] } {
+        Some(state) if timer.is_pending() => state,
+        Some(_) => return,
+        None => {
+            timer.disarm();
+            return;
+        }
+    };
+    match state.mode {
+        Mode::Periodic => timer.arm(state.interval as u64),
+        Mode::OneShot => {
+            timer.disarm();
+            interrupt::unregister_local(LocalInterrupt::PhysicalTimer);
+            unsafe {
+                LOCAL_STATE[core] = None;
+            }
+        }
+    }
+    (state.callback)();
+}
+
+///Which timer current_time and the sleep functions read from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeSource {
+    ///The Broadcom system timer, shared between all cores
+    SystemTimer,
+    ///The ARM generic timer of whichever core is asking
+    GenericTimer,
+}
+
+///The time source each core has chosen, indexed by core
+static mut TIME_SOURCE: [TimeSource; 4] = [TimeSource::SystemTimer; 4];
+
+///Chooses which timer current_time and the sleep functions read from on this core, the other
+///cores keep their own choice. The two timers don't start at the same time, so don't compare
+///times read before and after
+pub fn set_time_source(source: TimeSource) {
+    unsafe {
+        TIME_SOURCE[core_id()] = source;
+    }
+}
+
+///Gets the timer current_time and the sleep functions read from on this core
+pub fn time_source() -> TimeSource {
+    unsafe { TIME_SOURCE[core_id()] }
 }
 
 /// Gets the current time in microseconds
 pub fn current_time() -> u64 {
-    SystemTimer::new().read()
+    match time_source() {
+        TimeSource::SystemTimer => SystemTimer::new().read(),
+        TimeSource::GenericTimer => GenericTimer::new().read(),
+    }
 }
 
 /// Gets the current time in milliseconds
@@ -67,10 +399,12 @@ pub fn current_time_ms() -> u64 {
     current_time() / 1000
 }
 
-///Locks the thread for so many microseconds
+///Locks the thread for so many microseconds, on this core's time source
 pub fn spin_sleep_micros(micros: u64) {
-    let start = current_time();
-    while current_time() < start + micros {}
+    match time_source() {
+        TimeSource::SystemTimer => SystemTimer::new().spin_sleep_micros(micros),
+        TimeSource::GenericTimer => GenericTimer::new().spin_sleep_micros(micros),
+    }
 }
 
 ///Locks the thread for so many milliseconds
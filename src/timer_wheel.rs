@@ -0,0 +1,167 @@
+/// Software timers multiplexed onto system timer compare channel 3
+///
+/// Every scheduled timeout lives in a fixed table of slots, there is no allocator. The hardware
+/// channel is always armed for the earliest deadline in the table, when it fires every expired
+/// entry is run and the channel is re-armed for whatever is next. With this few slots a linear
+/// scan is cheaper than keeping a sorted queue.
+
+use arch::without_interrupts;
+use timer::{self, Channel, Mode, SystemTimer};
+
+///How many timeouts can be scheduled at once
+const SLOTS: usize = 64;
+
+///Shortest delay the compare channel is armed for, anything sooner could be missed because CLO
+///has already gone past the compare value by the time it is written
+const MIN_DELAY: u64 = 10;
+
+///The hardware compare channel this is built on, channel 1 is left free for other users
+const CHANNEL: Channel = Channel::Three;
+
+///One scheduled timeout
+#[derive(Clone, Copy)]
+struct Entry {
+    ///Time in microseconds on the system timer this entry is due
+    deadline: u64,
+    ///Some for periodic entries, the time between runs in microseconds
+    period: Option<u64>,
+    callback: fn(),
+    ///Bumped every time the slot is freed so old handles to it stop working
+    generation: u32,
+    active: bool,
+}
+
+fn noop() {}
+
+const EMPTY: Entry = Entry {
+    deadline: 0,
+    period: None,
+    callback: noop,
+    generation: 0,
+    active: false,
+};
+
+///Statically allocated storage for every timeout
+static mut ENTRIES: [Entry; SLOTS] = [EMPTY; SLOTS];
+
+///Returned when a timeout is scheduled, used to cancel it later.
+///Handles are only valid until the timeout fires (for one shots) or is cancelled, after that
+///they refer to nothing even if the slot gets reused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimerHandle {
+    slot: usize,
+    generation: u32,
+}
+
+///Runs the callback from IRQ context once, in so many microseconds.
+///This fails if every slot is already in use
+pub fn schedule_after(micros: u64, callback: fn()) -> Result<TimerHandle, ()> {
+    schedule(micros, None, callback)
+}
+
+///Runs the callback from IRQ context every so many microseconds until it is cancelled.
+///Periods shorter than MIN_DELAY are stretched to it, a period of 0 would always be due and
+///never let the handler return. This fails if every slot is already in use
+pub fn schedule_every(micros: u64, callback: fn()) -> Result<TimerHandle, ()> {
+    let period = micros.max(MIN_DELAY);
+    schedule(period, Some(period), callback)
+}
+
+fn schedule(micros: u64, period: Option<u64>, callback: fn()) -> Result<TimerHandle, ()> {
+    without_interrupts(|| {
+        let entries = unsafe { &mut ENTRIES };
+        let slot = match entries.iter().position(|entry| !entry.active) {
+            Some(slot) => slot,
+            None => return Err(()),
+        };
+        let entry = &mut entries[slot];
+        entry.deadline = SystemTimer::new().read() + micros;
+        entry.period = period;
+        entry.callback = callback;
+        entry.active = true;
+        let handle = TimerHandle {
+            slot,
+            generation: entry.generation,
+        };
+        rearm();
+        Ok(handle)
+    })
+}
+
+///Stops a timeout from running. Returns false if it had already fired or been cancelled
+pub fn cancel(handle: TimerHandle) -> bool {
+    without_interrupts(|| {
+        if !is_scheduled(handle) {
+            return false;
+        }
+        unsafe { free(&mut ENTRIES[handle.slot]) };
+        rearm();
+        true
+    })
+}
+
+///Checks if the timeout this handle refers to is still waiting to run
+pub fn is_scheduled(handle: TimerHandle) -> bool {
+    let entry = unsafe { &ENTRIES[handle.slot] };
+    entry.active && entry.generation == handle.generation
+}
+
+fn free(entry: &mut Entry) {
+    entry.active = false;
+    entry.generation = entry.generation.wrapping_add(1);
+}
+
+///Arms the compare channel for the earliest deadline, or disarms it if nothing is scheduled.
+///Must be called with interrupts disabled
+fn rearm() {
+    let entries = unsafe { &ENTRIES };
+    let next = entries
+        .iter()
+        .filter(|entry| entry.active)
+        .map(|entry| entry.deadline)
+        .min();
+    match next {
+        Some(deadline) => {
+            let now = SystemTimer::new().read();
+            let delay = if deadline > now + MIN_DELAY {
+                deadline - now
+            } else {
+                MIN_DELAY
+            };
+            //The compare registers are only 32 bits, anything further away than that just
+            //wakes up early and re-arms
+            let delay = if delay > u32::max_value() as u64 {
+                u32::max_value()
+            } else {
+                delay as u32
+            };
+            timer::arm(CHANNEL, delay, Mode::OneShot, on_compare);
+        }
+        None => timer::disarm(CHANNEL),
+    }
+}
+
+///Interrupt handler for the compare channel, runs everything that is due
+fn on_compare() {
+    let now = SystemTimer::new().read();
+    for slot in 0..SLOTS {
+        let entry = unsafe { &mut ENTRIES[slot] };
+        if !entry.active || entry.deadline > now {
+            continue;
+        }
+        let callback = entry.callback;
+        match entry.period {
+            Some(period) => {
+                //Keep to the original schedule unless we're so late a whole period was missed
+                entry.deadline += period;
+                if entry.deadline <= now {
+                    entry.deadline = now + period;
+                }
+            }
+            None => free(entry),
+        }
+        //The entry is updated first so the callback is free to cancel or reschedule itself
+        callback();
+    }
+    rearm();
+}
@@ -1,7 +1,12 @@
+use arch::{disable_irqs, enable_irqs, irqs_disabled, wfi};
 use common::{AUX_ENABLES, MU_REG_BASE};
 use core::fmt;
-use gpio::{AltFunction, Gpio};
-use stdio::{stdin, stdout};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use gpio::{Alt, AltFunction, Gpio};
+use interrupt::{self, Interrupt};
+use mailbox::{self, ClockId};
+use ring_buffer::RingBuffer;
+use serial::{LineStatus, SerialPort};
 use volatile::{ReadOnly, ReadWrite, Volatile, WriteOnly};
 
 ///Auxiliary peripherals Register Map as defined on page 205 figure 2.1 of the Broadcom manual
@@ -58,16 +63,168 @@ struct Registers {
     _j: [u8; 2],
 }
 
+///MU_IER_REG bits. The Broadcom manual has the receive and transmit bits the wrong way round,
+///and bits 3:2 are documented as unused but have to be set for any interrupt to be raised
+const IER_RX: u8 = 0b1101;
+const IER_TX: u8 = 0b0010;
+
+///Bytes received by the interrupt handler waiting to be read
+static RX_BUFFER: RingBuffer = RingBuffer::new();
+///Bytes written by the kernel waiting for the interrupt handler to send them
+static TX_BUFFER: RingBuffer = RingBuffer::new();
+///If the device has been switched to interrupt driven mode with with_interrupts
+static INTERRUPT_DRIVEN: AtomicBool = AtomicBool::new(false);
+///Number of bytes the interrupt handler had to throw away because RX_BUFFER was full
+static RX_OVERRUNS: AtomicUsize = AtomicUsize::new(0);
+
+fn registers() -> &'static mut Registers {
+    unsafe { &mut *(MU_REG_BASE as *mut Registers) }
+}
+
+///Number of bits in each character, set in MU_LCR_REG
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataBits {
+    Seven,
+    Eight,
+}
+
+///Where the clock the baud rate divisor is calculated from comes from.
+///The Mini Uart runs off the VPU core clock, which the firmware can change (core_freq in
+///config.txt), so by default the rate is asked for over the mailbox
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockSource {
+    ///Ask the firmware for the core clock rate
+    Mailbox,
+    ///Assume the core clock runs at this many Hz
+    Fixed(u32),
+}
+
+///Settings for the Mini Uart, built with the builder pattern and applied with Uart::configure
+///
+///x = UartConfig::new().baud_rate(921600).data_bits(DataBits::Eight);
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UartConfig {
+    baud_rate: u32,
+    data_bits: DataBits,
+    clock_source: ClockSource,
+}
+
+impl UartConfig {
+    ///Constructor, 115200 baud 8 bit with the clock rate from the mailbox
+    pub fn new() -> UartConfig {
+        UartConfig {
+            baud_rate: 115200,
+            data_bits: DataBits::Eight,
+            clock_source: ClockSource::Mailbox,
+        }
+    }
+    ///Set the baud rate to aim for, the achieved rate is reported by Uart::configure
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+    ///Set the number of bits per character
+    pub fn data_bits(mut self, data_bits: DataBits) -> Self {
+        self.data_bits = data_bits;
+        self
+    }
+    ///Set where the core clock rate comes from
+    pub fn clock_source(mut self, clock_source: ClockSource) -> Self {
+        self.clock_source = clock_source;
+        self
+    }
+}
+
+///What Uart::configure actually managed to set up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BaudReport {
+    ///The core clock rate the divisor was worked out from, in Hz
+    pub clock: u32,
+    ///The value written to MU_BAUD_REG
+    pub divisor: u16,
+    ///The baud rate this divisor gives
+    pub achieved: u32,
+    ///How far the achieved rate is from the requested one in parts per million, most receivers
+    ///cope with a few percent either way
+    pub error_ppm: i32,
+}
+
+impl fmt::Display for BaudReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.error_ppm < 0 { '-' } else { '+' };
+        let error = self.error_ppm.abs();
+        write!(
+            f,
+            "{} baud (divisor {}, clock {}Hz, error {}{}.{:02}%)",
+            self.achieved,
+            self.divisor,
+            self.clock,
+            sign,
+            error / 10000,
+            (error % 10000) / 100
+        )
+    }
+}
+
+///Reasons Uart::configure can fail, the device is left as it was
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigError {
+    ///The firmware didn't answer the mailbox request for the core clock rate
+    ClockUnavailable,
+    ///The baud rate can't be reached from this clock with a 16 bit divisor
+    BaudRateOutOfRange,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::ClockUnavailable => write!(f, "the firmware didn't give the clock rate"),
+            ConfigError::BaudRateOutOfRange => {
+                write!(f, "the baud rate can't be reached from the clock")
+            }
+        }
+    }
+}
+
+///Claims one of the Mini Uart's pins and switches it over to the Uart
+fn claim_pin(pin: u8) -> Gpio<Alt> {
+    match Gpio::claim(pin, "mini uart") {
+        Ok(gpio) => gpio.as_alt(AltFunction(5)),
+        Err(e) => panic!("Mini Uart can't start: {}", e),
+    }
+}
+
+///Takes one of the Mini Uart's pins whoever holds it, for Uart::steal
+fn steal_pin(pin: u8) -> Gpio<Alt> {
+    Gpio::steal(pin, "mini uart").as_alt(AltFunction(5))
+}
 
 ///Wrapper for the Uart registers
 pub struct Uart {
     registers: &'static mut Registers,
+    ///TX and RX, held so nothing else can take them while the device exists
+    _pins: [Gpio<Alt>; 2],
+    ///CTS and RTS when auto flow control is on
+    flow_control_pins: Option<[Gpio<Alt>; 2]>,
+    ///Timeout in milliseconds for the timed read functions, None waits forever
     timeout: Option<u32>,
 }
 
 impl Uart {
-    ///Constructor
+    ///Constructor. Claims GPIO14 and 15, panicking if another driver holds them, and gives them
+    ///back when the Uart is dropped
     pub fn new() -> Uart {
+        Uart::start(claim_pin)
+    }
+
+    ///Constructor for the panic handler. Takes GPIO14 and 15 even if another driver holds them,
+    ///so a panic caused by the pins being taken can't panic again trying to report it
+    pub fn steal() -> Uart {
+        Uart::start(steal_pin)
+    }
+
+    ///Sets the device up, getting hold of each pin with take_pin
+    fn start(take_pin: fn(u8) -> Gpio<Alt>) -> Uart {
         let registers = unsafe {
             
             let mut aux = AUX_ENABLES as *mut u8 as *mut Volatile<u8>;
@@ -77,19 +234,26 @@ impl Uart {
             &mut *(MU_REG_BASE as *mut Registers)
         };
         
-        //set datasize to 8 bit
+        //set datasize to 8 bit and 115200 baud, assuming the default 250MHz core clock.
+        //Use configure to work it out from the real clock rate
         registers.MU_LCR_REG.write(0b11);
         registers.MU_BAUD_REG.write(270);
 
-        //Create new Gpio pins and set them to use AltFunction 5, see page 102 of Broadcom manual
-        Gpio::new(14).as_alt(AltFunction(5));
-        Gpio::new(15).as_alt(AltFunction(5));
+        //Claim the Gpio pins and set them to use AltFunction 5, see page 102 of Broadcom manual
+        let pins = [take_pin(14), take_pin(15)];
 
         //Enable TX|RX pins
         registers.MU_CNTL_REG.write(0b11); 
 
+        //Start off polled, the interrupt handler might still be registered from a previous
+        //instance so make sure it has nothing to do
+        INTERRUPT_DRIVEN.store(false, Ordering::SeqCst);
+        registers.MU_IER_REG.write(0);
+
         Uart {
             registers,
+            _pins: pins,
+            flow_control_pins: None,
             timeout: None,
         }
     }
@@ -98,29 +262,101 @@ impl Uart {
     ///
     ///Uses builder pattern to make a nice to use constructor, feeding in one into the other like
     ///x = a().b().c();
-    pub fn with_auto_flow_control(self) -> Self {
-        //Create new Gpio pins and set them to use AltFunction 5, see page 102 of Broadcom manual
-        Gpio::new(16).as_alt(AltFunction(5));
-        Gpio::new(17).as_alt(AltFunction(5));
+    pub fn with_auto_flow_control(mut self) -> Self {
+        //Claim the Gpio pins and set them to use AltFunction 5, see page 102 of Broadcom manual
+        if self.flow_control_pins.is_none() {
+            self.flow_control_pins = Some([claim_pin(16), claim_pin(17)]);
+        }
         //Set CTS assert polarity, RTS assert polarity, flow level, enable CTS and RTS and enable
         //TX and RX (probs already on from new())
         self.registers.MU_CNTL_REG.write(0b11111111); 
         //return modified structure
         self
     }
-    ///Set a timeout for this Uart device via builder pattern
-    pub fn with_timeout(mut self, timeout: u32) -> Self {
-        self.timeout = Some(timeout);
+    ///Switches the device to interrupt driven mode via builder pattern.
+    ///
+    ///Received bytes are collected into a ring buffer by the AUX interrupt as soon as they
+    ///arrive, so none are lost while the kernel is busy, and written bytes are queued and sent
+    ///in the background. Creating a new Uart switches back to polled mode.
+    pub fn with_interrupts(self) -> Self {
+        INTERRUPT_DRIVEN.store(true, Ordering::SeqCst);
+        interrupt::register(Interrupt::Aux, aux_irq);
+        self.registers.MU_IER_REG.write(IER_RX);
         self
     }
-    ///Set a timeout for this Uart device
-    pub fn set_timeout(&mut self, timeout: u32) {
+
+    ///Checks if the device is in interrupt driven mode
+    pub fn is_interrupt_driven(&self) -> bool {
+        INTERRUPT_DRIVEN.load(Ordering::SeqCst)
+    }
+
+    ///Number of received bytes thrown away because the receive buffer was full
+    pub fn rx_overruns(&self) -> usize {
+        RX_OVERRUNS.load(Ordering::SeqCst)
+    }
+
+    ///Applies the baud rate and data size from the config, reporting the baud rate achieved.
+    ///
+    ///The Mini Uart baud rate is core clock / (8 * (MU_BAUD_REG + 1)), see page 11 of the
+    ///Broadcom manual, so only some rates can be hit exactly
+    pub fn configure(&mut self, config: &UartConfig) -> Result<BaudReport, ConfigError> {
+        let clock = match config.clock_source {
+            ClockSource::Mailbox => match mailbox::clock_rate(ClockId::Core) {
+                Ok(clock) => clock,
+                Err(_) => return Err(ConfigError::ClockUnavailable),
+            },
+            ClockSource::Fixed(clock) => clock,
+        };
+        if config.baud_rate == 0 {
+            return Err(ConfigError::BaudRateOutOfRange);
+        }
+
+        //Round to the nearest divisor rather than always down
+        let step = 8 * config.baud_rate as u64;
+        let divisor = (clock as u64 + step / 2) / step;
+        if divisor < 1 || divisor > 0x10000 {
+            return Err(ConfigError::BaudRateOutOfRange);
+        }
+        let divisor = (divisor - 1) as u16;
+        let achieved = clock / (8 * (divisor as u32 + 1));
+        let error_ppm = (achieved as i64 - config.baud_rate as i64) * 1_000_000
+            / config.baud_rate as i64;
+
+        //Let anything already queued go out at the old rate, then turn the transmitter and
+        //receiver off while the settings change
+        self.flush();
+        let control = self.registers.MU_CNTL_REG.read();
+        self.registers.MU_CNTL_REG.write(0);
+        self.registers.MU_LCR_REG.write(match config.data_bits {
+            DataBits::Seven => 0b00,
+            //Both bits have to be set for 8 bit mode, the manual only mentions bit 0
+            DataBits::Eight => 0b11,
+        });
+        self.registers.MU_BAUD_REG.write(divisor);
+        self.registers.MU_CNTL_REG.write(control);
+
+        Ok(BaudReport {
+            clock,
+            divisor,
+            achieved,
+            error_ppm: error_ppm as i32,
+        })
+    }
+
+    ///Set a timeout in milliseconds for the timed read functions via builder pattern
+    pub fn with_timeout(mut self, timeout: u32) -> Self {
         self.timeout = Some(timeout);
+        self
     }
 
-    ///Writes a u8 byte to the device, blocking until written successfully
-    pub fn write_byte(&mut self, b: u8) {
+    ///Takes a byte off the transmit buffer and sends it, blocking until the transmitter is free
+    fn send_buffered_byte(&mut self) {
+        if let Some(b) = TX_BUFFER.pop() {
+            self.write_byte_polled(b);
+        }
+    }
 
+    fn write_byte_polled(&mut self, b: u8) {
         //While bit 5 of MU_LSR_REG is low the transmitter is not available, so spinlock the thread
         //See page 15 of Broadcom manual
         while self.registers.MU_LSR_REG.read() & (1 << 5) == 0 {}
@@ -128,35 +364,154 @@ impl Uart {
         //See page page 11 of Broadcom manual
         self.registers.MU_IO_REG.write(b);
     }
+}
+
+impl SerialPort for Uart {
+    ///Writes a u8 byte to the device, blocking until written successfully.
+    ///In interrupt driven mode this only blocks until there is space in the transmit buffer
+    fn write_byte(&mut self, b: u8) {
+        if self.is_interrupt_driven() {
+            while TX_BUFFER.push(b).is_err() {
+                if irqs_disabled() {
+                    //The interrupt handler can't run to make space, so send one ourselves
+                    self.send_buffered_byte();
+                }
+            }
+            self.registers.MU_IER_REG.write(IER_RX | IER_TX);
+            return;
+        }
+        self.write_byte_polled(b);
+    }
+
+    fn try_write_byte(&mut self, b: u8) -> Result<(), ()> {
+        if self.is_interrupt_driven() {
+            TX_BUFFER.push(b)?;
+            self.registers.MU_IER_REG.write(IER_RX | IER_TX);
+            return Ok(());
+        }
+        match self.registers.MU_LSR_REG.read() & (1 << 5) {
+            0 => Err(()),
+            _ => {
+                self.registers.MU_IO_REG.write(b);
+                Ok(())
+            }
+        }
+    }
 
-    ///If bit 0 of the MU_LSR_REG is high there is data waiting to be read out
-    pub fn has_byte(&self) -> bool {
+    ///If bit 0 of the MU_LSR_REG is high there is data waiting to be read out.
+    ///In interrupt driven mode the interrupt handler has already moved it to the receive buffer
+    fn has_byte(&self) -> bool {
+        if self.is_interrupt_driven() {
+            return !RX_BUFFER.is_empty();
+        }
         //Use a binary AND operation to read only 1 bit
         self.registers.MU_LSR_REG.read() & (1 << 0) == 1
     }
 
-    ///Read the incoming data from the device
-    pub fn read_byte(&self) -> u8 {
-
+    ///Read the incoming data from the device, waiting for it to arrive.
+    ///In interrupt driven mode the core sleeps until then. With IRQs masked the handler can't fill
+    ///the receive buffer, so the byte is read from the device directly instead
+    fn read_byte(&mut self) -> u8 {
+        if self.is_interrupt_driven() {
+            loop {
+                if let Some(b) = RX_BUFFER.pop() {
+                    return b;
+                }
+                if irqs_disabled() {
+                    if self.registers.MU_LSR_REG.read() & (1 << 0) != 0 {
+                        return self.registers.MU_IO_REG.read();
+                    }
+                    continue;
+                }
+                //Checking again with IRQs masked means a byte arriving just now still wakes us
+                //up instead of being missed
+                disable_irqs();
+                if RX_BUFFER.is_empty() {
+                    wfi();
+                }
+                enable_irqs();
+            }
+        }
+        while !self.has_byte() {}
         self.registers.MU_IO_REG.read()
     }
 
-    ///Pushes the data onto stdin directly
-    pub fn read_to_stdin(&self) {
-        //Get new stdin handle
-        let mut stdin = stdin().unwrap();
-        while self.has_byte() {
-            stdin.push(self.read_byte()).unwrap();
+    ///Blocks until every queued byte has been sent and the transmitter is idle
+    fn flush(&mut self) {
+        if self.is_interrupt_driven() {
+            while !TX_BUFFER.is_empty() {
+                if irqs_disabled() {
+                    self.send_buffered_byte();
+                }
+            }
+        }
+        //Bit 6 of MU_LSR_REG is set when the transmit FIFO is empty and the transmitter idle
+        while self.registers.MU_LSR_REG.read() & (1 << 6) == 0 {}
+    }
+
+    ///Built from MU_LSR_REG, see page 15 of the Broadcom manual. In interrupt driven mode the
+    ///software buffers count too. The Mini Uart can't detect a break
+    fn line_status(&self) -> LineStatus {
+        let lsr = self.registers.MU_LSR_REG.read();
+        let buffered = self.is_interrupt_driven();
+        LineStatus {
+            data_ready: self.has_byte(),
+            can_transmit: match buffered {
+                true => !TX_BUFFER.is_full(),
+                false => lsr & (1 << 5) != 0,
+            },
+            transmitter_idle: lsr & (1 << 6) != 0 && (!buffered || TX_BUFFER.is_empty()),
+            overrun: lsr & (1 << 1) != 0 || self.rx_overruns() > 0,
+            break_received: false,
         }
     }
-    
-    ///Write all data in stdout to the device then clear it
-    pub fn flush_stdout(&mut self) {
-        let mut stdout = stdout().unwrap();
-        stdout.into_iter().for_each(|&x| self.write_byte(x));
-        stdout.clear();
+
+    ///Bit 6 of MU_LCR_REG pulls the transmit line low, see page 14 of the Broadcom manual
+    fn send_break(&mut self, on: bool) {
+        let lcr = self.registers.MU_LCR_REG.read();
+        self.registers.MU_LCR_REG.write(match on {
+            true => lcr | (1 << 6),
+            false => lcr & !(1 << 6),
+        });
+    }
+
+    fn timeout(&self) -> Option<u32> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: u32) {
+        self.timeout = Some(timeout);
+    }
+
+    fn clear_timeout(&mut self) {
+        self.timeout = None;
     }
 }
+
+///Interrupt handler for the AUX peripherals, moves received bytes into RX_BUFFER and refills the
+///transmit FIFO from TX_BUFFER. The transmit interrupt is turned off once there is nothing left to
+///send, otherwise it would fire continuously while the FIFO is empty
+fn aux_irq() {
+    let registers = registers();
+    //Bit 0 of MU_LSR_REG: data ready
+    while registers.MU_LSR_REG.read() & (1 << 0) != 0 {
+        let b = registers.MU_IO_REG.read();
+        if RX_BUFFER.push(b).is_err() {
+            RX_OVERRUNS.store(RX_OVERRUNS.load(Ordering::SeqCst) + 1, Ordering::SeqCst);
+        }
+    }
+    //Bit 5 of MU_LSR_REG: transmit FIFO can accept at least one byte
+    while registers.MU_LSR_REG.read() & (1 << 5) != 0 {
+        match TX_BUFFER.pop() {
+            Some(b) => registers.MU_IO_REG.write(b),
+            None => {
+                registers.MU_IER_REG.write(IER_RX);
+                break;
+            }
+        }
+    }
+}
+
 ///Implement write for Uart, allowing access to many methods for writing different types of output
 ///through the device
 impl fmt::Write for Uart {